@@ -0,0 +1,586 @@
+use crate::{config, file, money::Money, query::SearchQuery};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+/// Command line flag used to pass the catalog path.
+pub const CATALOG_FLAG: &str = "--catalog";
+/// Environment variable used when the flag is not present.
+pub const CATALOG_ENV: &str = "TRAVEL_AGENCY_CATALOG";
+/// Catalog used when neither the flag nor the variable are set.
+pub const DEFAULT_CATALOG: &str = "catalog.csv";
+
+const HEADER: [&str; 4] = ["from", "to", "price", "capacity"];
+/// Marks a trip that is no longer sold, in an extra column.
+const RETIRED: &str = "retired";
+/// Extension of the file next to the catalog that keeps the sold seats.
+const SOLD_EXTENSION: &str = "sold";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Trip {
+    /// Position of the trip in its catalog.
+    pub id: usize,
+    pub from: String,
+    pub to: String,
+    pub price: Money,
+    pub capacity: usize,
+    /// No longer sold, kept so that the ids of the other trips do not change.
+    #[serde(default)]
+    pub retired: bool,
+}
+
+impl Trip {
+    pub fn new(from: String, to: String, price: Money, capacity: usize) -> Self {
+        Self {
+            id: 0,
+            from,
+            to,
+            price,
+            capacity,
+            retired: false,
+        }
+    }
+
+    pub fn matches(&self, city: &str) -> bool {
+        self.from == city || self.to == city
+    }
+}
+
+#[derive(Debug)]
+pub struct LineError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug)]
+pub enum CatalogError {
+    Io(PathBuf, io::Error),
+    Invalid(Vec<LineError>),
+}
+
+impl fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CatalogError::Io(path, err) => {
+                write!(f, "could not read catalog {}: {}", path.display(), err)
+            }
+            CatalogError::Invalid(errors) => {
+                write!(f, "invalid catalog:")?;
+                for error in errors {
+                    write!(f, "\n  {}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for CatalogError {}
+
+/// Why [`Catalog::reserve`] took no seats.
+#[derive(Debug)]
+pub enum ReserveError {
+    /// The first trip without free seats.
+    SoldOut(Trip),
+    /// A leg whose trip is no longer in the catalog with the same cities.
+    Changed(Trip),
+    /// The seats could not be saved.
+    Save(String),
+}
+
+impl fmt::Display for ReserveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReserveError::SoldOut(trip) => write!(f, "{} -> {} is sold out", trip.from, trip.to),
+            ReserveError::Changed(trip) => {
+                write!(
+                    f,
+                    "{} -> {} is no longer in the catalog",
+                    trip.from, trip.to
+                )
+            }
+            ReserveError::Save(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// The trips the agency can sell and how many seats were sold for each.
+///
+/// Catalogs are CSV files with one `from,to,price,capacity` trip per line,
+/// prices are written like `450.50 EUR` and default to euros.
+/// Trips no longer sold have an extra `retired` column.
+/// Blank lines and lines starting with `#` are ignored,
+/// and an optional header with those column names may come before the first trip.
+///
+/// Sold seats are kept next to the catalog, `catalog.sold` for `catalog.csv`,
+/// with one `id,sold` line per trip that sold any.
+#[derive(Clone, Debug, Default)]
+pub struct Catalog {
+    trips: Vec<Trip>,
+    sold: Vec<usize>,
+    /// Where changes are saved, catalogs that were not loaded from a file are not saved.
+    path: Option<PathBuf>,
+}
+
+impl Catalog {
+    pub fn new(mut trips: Vec<Trip>) -> Self {
+        for (id, trip) in trips.iter_mut().enumerate() {
+            trip.id = id;
+        }
+        let sold = vec![0; trips.len()];
+        Self {
+            trips,
+            sold,
+            path: None,
+        }
+    }
+
+    /// Load the catalog from the path given by `--catalog <path>`,
+    /// the `TRAVEL_AGENCY_CATALOG` variable or [`DEFAULT_CATALOG`], in that order.
+    pub fn from_env() -> Result<Self, CatalogError> {
+        Self::load(config::path_arg(CATALOG_FLAG, CATALOG_ENV, DEFAULT_CATALOG))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CatalogError> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|err| CatalogError::Io(path.to_path_buf(), err))?;
+        let mut catalog = Self::parse(BufReader::new(file)).map_err(|err| match err {
+            CatalogError::Io(_, err) => CatalogError::Io(path.to_path_buf(), err),
+            err => err,
+        })?;
+        catalog.sold = load_sold(&path.with_extension(SOLD_EXTENSION), catalog.trips.len())?;
+        catalog.path = Some(path.to_path_buf());
+        Ok(catalog)
+    }
+
+    pub fn parse<R: BufRead>(reader: R) -> Result<Self, CatalogError> {
+        let mut trips = vec![];
+        let mut errors = vec![];
+        // the header can only come before the first trip
+        let mut first = true;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.map_err(|err| CatalogError::Io(PathBuf::new(), err))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<_> = line.split(',').map(str::trim).collect();
+            let header = first && fields == HEADER;
+            first = false;
+            if header {
+                continue;
+            }
+            match parse_trip(&fields) {
+                Ok(trip) => trips.push(trip),
+                Err(message) => errors.push(LineError {
+                    line: i + 1,
+                    message,
+                }),
+            }
+        }
+        if errors.is_empty() {
+            Ok(Self::new(trips))
+        } else {
+            Err(CatalogError::Invalid(errors))
+        }
+    }
+
+    pub fn trips(&self) -> &[Trip] {
+        &self.trips
+    }
+
+    pub fn search(&self, query: &SearchQuery) -> Vec<Trip> {
+        let mut trips: Vec<_> = self
+            .trips
+            .iter()
+            .filter(|trip| !trip.retired && query.matches(trip))
+            .cloned()
+            .collect();
+        query.sort(&mut trips);
+        trips
+    }
+
+    /// The trip `trip` is a copy of, if the catalog still has it with the same cities.
+    pub fn current(&self, trip: &Trip) -> Option<&Trip> {
+        self.trips
+            .get(trip.id)
+            .filter(|known| known.from == trip.from && known.to == trip.to)
+    }
+
+    /// Free seats of `trip`, none once it is retired.
+    pub fn available(&self, trip: &Trip) -> usize {
+        if self.trips[trip.id].retired {
+            return 0;
+        }
+        // the capacity may have been lowered by hand below the seats sold
+        self.trips[trip.id]
+            .capacity
+            .saturating_sub(self.sold[trip.id])
+    }
+
+    /// Seats sold for `trip`.
+    pub fn sold(&self, trip: &Trip) -> usize {
+        self.sold[trip.id]
+    }
+
+    /// Take one seat in each of the `legs` and save the sold seats,
+    /// either all seats are taken or none is.
+    ///
+    /// The legs are copies made when they were selected, each has to still match
+    /// the cities of its trip in the catalog.
+    pub fn reserve(&mut self, legs: &[Trip]) -> Result<(), ReserveError> {
+        if let Some(trip) = legs.iter().find(|trip| self.current(trip).is_none()) {
+            return Err(ReserveError::Changed(trip.clone()));
+        }
+        for (i, trip) in legs.iter().enumerate() {
+            // the same trip may appear more than once
+            let taken = legs[..i].iter().filter(|leg| leg.id == trip.id).count();
+            if self.available(trip) <= taken {
+                return Err(ReserveError::SoldOut(self.trips[trip.id].clone()));
+            }
+        }
+        for trip in legs {
+            self.sold[trip.id] += 1;
+        }
+        if let Err(err) = self.save_sold() {
+            for trip in legs {
+                self.sold[trip.id] -= 1;
+            }
+            return Err(ReserveError::Save(err));
+        }
+        Ok(())
+    }
+
+    /// Give back the seats taken by [`Catalog::reserve`] and save the sold seats.
+    ///
+    /// The seats are given back for this process even if they cannot be saved.
+    pub fn release(&mut self, legs: &[Trip]) -> Result<(), String> {
+        for trip in legs {
+            self.sold[trip.id] -= 1;
+        }
+        self.save_sold()
+    }
+
+    /// Add a trip and save the catalog, returns the trip with its id.
+    pub fn add(&mut self, mut trip: Trip) -> Result<Trip, String> {
+        check_trip(&trip)?;
+        trip.id = self.trips.len();
+        self.trips.push(trip.clone());
+        self.sold.push(0);
+        if let Err(err) = self.save() {
+            self.trips.pop();
+            self.sold.pop();
+            return Err(err);
+        }
+        Ok(trip)
+    }
+
+    /// Change the cities and capacity of the trip `id` and save the catalog.
+    ///
+    /// The capacity cannot drop below the seats already sold, and the cities of a trip
+    /// that sold seats cannot change: add the new route and retire this trip instead.
+    pub fn edit(
+        &mut self,
+        id: usize,
+        from: &str,
+        to: &str,
+        capacity: usize,
+    ) -> Result<Trip, String> {
+        let sold = self.sold.get(id).copied().unwrap_or(0);
+        if capacity < sold {
+            return Err(format!("{} seats are already sold", sold));
+        }
+        let moved = self
+            .trips
+            .get(id)
+            .is_some_and(|trip| trip.from != from || trip.to != to);
+        if moved && sold > 0 {
+            return Err(format!(
+                "{} seats are already sold, add the new route and retire this trip instead",
+                sold
+            ));
+        }
+        self.update(id, |trip| {
+            trip.from = from.to_string();
+            trip.to = to.to_string();
+            trip.capacity = capacity;
+        })
+    }
+
+    /// Change the price of the trip `id` and save the catalog.
+    ///
+    /// Purchases are charged the prices of the catalog when they are made,
+    /// selections made before are charged the new price.
+    pub fn reprice(&mut self, id: usize, price: Money) -> Result<Trip, String> {
+        self.update(id, |trip| trip.price = price)
+    }
+
+    /// Stop selling the trip `id` and save the catalog.
+    pub fn retire(&mut self, id: usize) -> Result<Trip, String> {
+        self.update(id, |trip| trip.retired = true)
+    }
+
+    fn update<F: FnOnce(&mut Trip)>(&mut self, id: usize, change: F) -> Result<Trip, String> {
+        let trip = self
+            .trips
+            .get_mut(id)
+            .ok_or_else(|| format!("no trip with id {}", id))?;
+        let previous = trip.clone();
+        change(trip);
+        let trip = trip.clone();
+        let result = check_trip(&trip).and_then(|()| self.save());
+        if let Err(err) = result {
+            self.trips[id] = previous;
+            return Err(err);
+        }
+        Ok(trip)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut contents = format!("{}\n", HEADER.join(","));
+        for trip in &self.trips {
+            contents.push_str(&format!(
+                "{},{},{},{}",
+                trip.from, trip.to, trip.price, trip.capacity
+            ));
+            if trip.retired {
+                contents.push_str(&format!(",{}", RETIRED));
+            }
+            contents.push('\n');
+        }
+        file::write_atomic(path, contents)
+            .map_err(|err| format!("could not save the catalog {}: {}", path.display(), err))
+    }
+
+    fn save_sold(&self) -> Result<(), String> {
+        let path = match &self.path {
+            Some(path) => path.with_extension(SOLD_EXTENSION),
+            None => return Ok(()),
+        };
+        let mut contents = String::new();
+        for (id, sold) in self.sold.iter().enumerate() {
+            if *sold > 0 {
+                contents.push_str(&format!("{},{}\n", id, sold));
+            }
+        }
+        file::write_atomic(&path, contents)
+            .map_err(|err| format!("could not save the sold seats {}: {}", path.display(), err))
+    }
+}
+
+/// Read the seats sold for each of the `trips`, a missing file means none was sold.
+fn load_sold(path: &Path, trips: usize) -> Result<Vec<usize>, CatalogError> {
+    let mut sold = vec![0; trips];
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(sold),
+        Err(err) => return Err(CatalogError::Io(path.to_path_buf(), err)),
+    };
+    let mut errors = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let seats = line.split_once(',').and_then(|(id, seats)| {
+            let id = id.trim().parse::<usize>().ok()?;
+            Some((id, seats.trim().parse::<usize>().ok()?))
+        });
+        match seats {
+            Some((id, seats)) if id < trips => sold[id] = seats,
+            Some((id, _)) => errors.push(LineError {
+                line: i + 1,
+                message: format!("{}: no trip with id {}", path.display(), id),
+            }),
+            None => errors.push(LineError {
+                line: i + 1,
+                message: format!("{}: expected id,sold", path.display()),
+            }),
+        }
+    }
+    if errors.is_empty() {
+        Ok(sold)
+    } else {
+        Err(CatalogError::Invalid(errors))
+    }
+}
+
+fn parse_trip(fields: &[&str]) -> Result<Trip, String> {
+    let retired = match fields.get(HEADER.len()) {
+        None => false,
+        Some(&RETIRED) if fields.len() == HEADER.len() + 1 => true,
+        Some(_) => {
+            return Err(format!(
+                "expected {} fields ({}), optionally followed by {}, found {}",
+                HEADER.len(),
+                HEADER.join(","),
+                RETIRED,
+                fields.len()
+            ))
+        }
+    };
+    if fields.len() < HEADER.len() {
+        return Err(format!(
+            "expected {} fields ({}), found {}",
+            HEADER.len(),
+            HEADER.join(","),
+            fields.len()
+        ));
+    }
+    let (from, to, price, capacity) = (fields[0], fields[1], fields[2], fields[3]);
+    let price = price
+        .parse::<Money>()
+        .map_err(|err| format!("invalid price: {}", err))?;
+    let capacity = capacity
+        .parse::<usize>()
+        .map_err(|_| format!("invalid capacity: {}", capacity))?;
+    let mut trip = Trip::new(from.to_string(), to.to_string(), price, capacity);
+    trip.retired = retired;
+    check_trip(&trip)?;
+    Ok(trip)
+}
+
+fn check_trip(trip: &Trip) -> Result<(), String> {
+    if trip.from.is_empty() || trip.to.is_empty() {
+        return Err("city names cannot be empty".to_string());
+    }
+    if trip.from.contains(',') || trip.to.contains(',') {
+        return Err("city names cannot contain ','".to_string());
+    }
+    if trip.from == trip.to {
+        return Err(format!("trip starts and ends in {}", trip.from));
+    }
+    if trip.price.is_negative() {
+        return Err(format!("negative price: {}", trip.price));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Currency;
+
+    fn catalog() -> Catalog {
+        let trip = |from: &str, to: &str| {
+            Trip::new(
+                from.to_string(),
+                to.to_string(),
+                Money::from_major(200, Currency::EUR).unwrap(),
+                2,
+            )
+        };
+        Catalog::new(vec![trip("Lisbon", "London"), trip("London", "Berlin")])
+    }
+
+    fn parse(contents: &str) -> Result<Catalog, CatalogError> {
+        Catalog::parse(contents.as_bytes())
+    }
+
+    #[test]
+    fn parses_trips_after_comments_and_a_header() {
+        let catalog = parse(
+            "# trips of the agency\n\
+             \n\
+             from,to,price,capacity\n\
+             Lisbon,London,450.50 EUR,10\n\
+             London, Berlin ,120,0,retired\n",
+        )
+        .unwrap();
+        let trips = catalog.trips();
+        assert_eq!(trips.len(), 2);
+        assert_eq!((trips[0].id, trips[0].to.as_str()), (0, "London"));
+        assert_eq!(trips[0].price.to_string(), "450.50 EUR");
+        assert_eq!((trips[1].id, trips[1].to.as_str()), (1, "Berlin"));
+        assert!(trips[1].retired);
+    }
+
+    #[test]
+    fn reports_every_invalid_line() {
+        let errors = match parse(
+            "Lisbon,London,450,10\n\
+             # a comment still counts as a line\n\
+             from,to,price,capacity\n\
+             Lisbon,London\n\
+             Lisbon,London,cheap,10\n\
+             Lisbon,London,450,-1\n\
+             Lisbon,Lisbon,450,10\n\
+             Lisbon,,450,10\n\
+             Lisbon,London,-450,10\n\
+             Lisbon,London,450,10,sold out\n",
+        ) {
+            Err(CatalogError::Invalid(errors)) => errors,
+            other => panic!("{:?}", other),
+        };
+        let errors: Vec<_> = errors.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            errors,
+            [
+                "line 3: invalid price: invalid amount \"price\", expected e.g. 450 or 450.50 EUR",
+                "line 4: expected 4 fields (from,to,price,capacity), found 2",
+                "line 5: invalid price: invalid amount \"cheap\", expected e.g. 450 or 450.50 EUR",
+                "line 6: invalid capacity: -1",
+                "line 7: trip starts and ends in Lisbon",
+                "line 8: city names cannot be empty",
+                "line 9: negative price: -450.00 EUR",
+                "line 10: expected 4 fields (from,to,price,capacity), \
+                 optionally followed by retired, found 5",
+            ]
+        );
+    }
+
+    #[test]
+    fn reserves_all_seats_or_none() {
+        let mut catalog = catalog();
+        let legs = catalog.trips().to_vec();
+        catalog.reserve(&legs).unwrap();
+        catalog.reserve(&legs[..1]).unwrap();
+        assert!(matches!(
+            catalog.reserve(&legs),
+            Err(ReserveError::SoldOut(trip)) if trip.id == 0
+        ));
+        assert_eq!(catalog.sold(&legs[0]), 2);
+        assert_eq!(catalog.sold(&legs[1]), 1);
+        catalog.release(&legs[..1]).unwrap();
+        assert_eq!(catalog.available(&legs[0]), 1);
+    }
+
+    #[test]
+    fn only_reserves_trips_that_kept_their_cities() {
+        let mut catalog = catalog();
+        let selected = catalog.trips()[0].clone();
+        catalog.edit(0, "Lisbon", "Paris", 2).unwrap();
+        assert!(matches!(
+            catalog.reserve(&[selected]),
+            Err(ReserveError::Changed(trip)) if trip.to == "London"
+        ));
+        let mut unknown = catalog.trips()[1].clone();
+        unknown.id = 7;
+        assert!(matches!(
+            catalog.reserve(&[unknown]),
+            Err(ReserveError::Changed(_))
+        ));
+    }
+
+    #[test]
+    fn keeps_the_route_of_trips_with_sold_seats() {
+        let mut catalog = catalog();
+        let legs = catalog.trips()[..1].to_vec();
+        catalog.reserve(&legs).unwrap();
+        assert!(catalog.edit(0, "Lisbon", "Paris", 2).is_err());
+        assert!(catalog.edit(0, "Lisbon", "London", 0).is_err());
+        let trip = catalog.edit(0, "Lisbon", "London", 5).unwrap();
+        assert_eq!(trip.capacity, 5);
+        assert_eq!(catalog.trips()[0].to, "London");
+    }
+}
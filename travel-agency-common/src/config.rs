@@ -0,0 +1,17 @@
+use std::{env, path::PathBuf};
+
+/// Resolve a file path from `<flag> <path>` in the command line arguments,
+/// the `env` variable or `default`, in that order.
+pub fn path_arg(flag: &str, env: &str, default: &str) -> PathBuf {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            if let Some(path) = args.next() {
+                return path.into();
+            }
+        }
+    }
+    env::var_os(env)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| default.into())
+}
@@ -0,0 +1,13 @@
+use std::{fs, io, path::Path};
+
+/// Replace the contents of the file at `path`.
+///
+/// The contents are written next to it first and then renamed over it,
+/// so a crash never leaves a half written file behind.
+pub fn write_atomic<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(tmp, path)
+}
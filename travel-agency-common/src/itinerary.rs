@@ -0,0 +1,257 @@
+use crate::{
+    catalog::{Catalog, Trip},
+    money::{Money, MoneyError},
+};
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+/// Longest chain of trips considered when planning a route.
+pub const MAX_LEGS: usize = 4;
+/// Number of itineraries returned by a route search.
+pub const MAX_ITINERARIES: usize = 5;
+
+/// One or more trips, each leaving from the city the previous one arrives at.
+///
+/// Itineraries are what the agency sells, a direct trip is a single leg itinerary.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Itinerary {
+    pub legs: Vec<Trip>,
+}
+
+impl Itinerary {
+    pub fn new(legs: Vec<Trip>) -> Self {
+        debug_assert!(!legs.is_empty());
+        Self { legs }
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.legs[0].from
+    }
+
+    pub fn destination(&self) -> &str {
+        &self.legs[self.legs.len() - 1].to
+    }
+
+    /// Sum of the leg prices, which fails when they are in different currencies.
+    pub fn price(&self) -> Result<Money, MoneyError> {
+        Money::sum(
+            self.legs[0].price.currency(),
+            self.legs.iter().map(|trip| trip.price),
+        )
+    }
+}
+
+impl From<Trip> for Itinerary {
+    fn from(trip: Trip) -> Self {
+        Self::new(vec![trip])
+    }
+}
+
+impl fmt::Display for Itinerary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.origin())?;
+        for trip in &self.legs {
+            write!(f, " -> {}", trip.to)?;
+        }
+        match self.price() {
+            Ok(price) => write!(f, " ({})", price),
+            Err(err) => write!(f, " ({})", err),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RouteOrder {
+    #[default]
+    Cheapest,
+    FewestLegs,
+}
+
+impl RouteOrder {
+    fn compare(
+        self,
+        (a, a_price): &(Itinerary, Money),
+        (b, b_price): &(Itinerary, Money),
+    ) -> Ordering {
+        // cheaper in the same currency, otherwise grouped by currency
+        let by_price =
+            (a_price.currency(), a_price.minor()).cmp(&(b_price.currency(), b_price.minor()));
+        let by_legs = a.legs.len().cmp(&b.legs.len());
+        match self {
+            RouteOrder::Cheapest => by_price.then(by_legs),
+            RouteOrder::FewestLegs => by_legs.then(by_price),
+        }
+    }
+}
+
+impl FromStr for RouteOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cheapest" => Ok(RouteOrder::Cheapest),
+            "legs" => Ok(RouteOrder::FewestLegs),
+            _ => Err(format!("unknown order: {} (expected cheapest or legs)", s)),
+        }
+    }
+}
+
+impl Catalog {
+    /// Find the best itineraries from `from` to `to`, treating trips as edges between cities.
+    ///
+    /// Only itineraries with up to [`MAX_LEGS`] trips that never revisit a city are considered,
+    /// itineraries mixing currencies cannot be priced and are left out.
+    pub fn itineraries(&self, from: &str, to: &str, order: RouteOrder) -> Vec<Itinerary> {
+        let mut found = vec![];
+        let mut legs = vec![];
+        self.walk(from, to, &mut legs, &mut found);
+        let mut priced: Vec<_> = found
+            .into_iter()
+            .filter_map(|itinerary| {
+                let price = itinerary.price().ok()?;
+                Some((itinerary, price))
+            })
+            .collect();
+        priced.sort_by(|a, b| order.compare(a, b));
+        priced.truncate(MAX_ITINERARIES);
+        priced.into_iter().map(|(itinerary, _)| itinerary).collect()
+    }
+
+    fn walk<'a>(
+        &'a self,
+        at: &str,
+        to: &str,
+        legs: &mut Vec<&'a Trip>,
+        found: &mut Vec<Itinerary>,
+    ) {
+        if legs.len() == MAX_LEGS {
+            return;
+        }
+        for trip in self
+            .trips()
+            .iter()
+            .filter(|trip| trip.from == at && !trip.retired)
+        {
+            let visited = legs.iter().any(|leg| leg.from == trip.to) || trip.to == at;
+            if visited {
+                continue;
+            }
+            legs.push(trip);
+            if trip.to == to {
+                found.push(Itinerary::new(
+                    legs.iter().map(|&leg| leg.clone()).collect(),
+                ));
+            } else {
+                self.walk(&trip.to, to, legs, found);
+            }
+            legs.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog(trips: &str) -> Catalog {
+        Catalog::parse(trips.as_bytes()).unwrap()
+    }
+
+    fn routes(catalog: &Catalog, from: &str, to: &str, order: RouteOrder) -> Vec<String> {
+        catalog
+            .itineraries(from, to, order)
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    /// Three ways from Lisbon to Berlin, one through a retired trip and loops back to Lisbon.
+    fn network() -> Catalog {
+        catalog(
+            "Lisbon,London,100,1\n\
+             London,Berlin,100,1\n\
+             Lisbon,Berlin,250,1\n\
+             Lisbon,Madrid,10,1\n\
+             Madrid,London,10,1\n\
+             Madrid,Lisbon,1,1\n\
+             Berlin,Lisbon,1,1\n\
+             Lisbon,Paris,1,1,retired\n\
+             Paris,Berlin,1,1\n",
+        )
+    }
+
+    #[test]
+    fn orders_by_price_or_legs() {
+        let catalog = network();
+        assert_eq!(
+            routes(&catalog, "Lisbon", "Berlin", RouteOrder::Cheapest),
+            [
+                "Lisbon -> Madrid -> London -> Berlin (120.00 EUR)",
+                "Lisbon -> London -> Berlin (200.00 EUR)",
+                "Lisbon -> Berlin (250.00 EUR)",
+            ]
+        );
+        assert_eq!(
+            routes(&catalog, "Lisbon", "Berlin", RouteOrder::FewestLegs),
+            [
+                "Lisbon -> Berlin (250.00 EUR)",
+                "Lisbon -> London -> Berlin (200.00 EUR)",
+                "Lisbon -> Madrid -> London -> Berlin (120.00 EUR)",
+            ]
+        );
+    }
+
+    #[test]
+    fn never_revisits_a_city() {
+        let catalog = network();
+        for itinerary in catalog.itineraries("Madrid", "Berlin", RouteOrder::Cheapest) {
+            let mut cities: Vec<_> = itinerary.legs.iter().map(|trip| &trip.to).collect();
+            cities.push(&itinerary.legs[0].from);
+            let count = cities.len();
+            cities.sort();
+            cities.dedup();
+            assert_eq!(cities.len(), count, "{}", itinerary);
+        }
+        assert!(catalog
+            .itineraries("Lisbon", "Lisbon", RouteOrder::Cheapest)
+            .is_empty());
+    }
+
+    #[test]
+    fn skips_retired_trips() {
+        let catalog = network();
+        assert!(catalog
+            .itineraries("Lisbon", "Paris", RouteOrder::Cheapest)
+            .is_empty());
+    }
+
+    #[test]
+    fn stops_after_the_longest_chain() {
+        let catalog = catalog(
+            "Lisbon,Madrid,10,1\n\
+             Madrid,Paris,10,1\n\
+             Paris,London,10,1\n\
+             London,Berlin,10,1\n\
+             Berlin,Warsaw,10,1\n",
+        );
+        let longest = catalog.itineraries("Lisbon", "Berlin", RouteOrder::Cheapest);
+        assert_eq!(longest.len(), 1);
+        assert_eq!(longest[0].legs.len(), MAX_LEGS);
+        assert!(catalog
+            .itineraries("Lisbon", "Warsaw", RouteOrder::Cheapest)
+            .is_empty());
+    }
+
+    #[test]
+    fn leaves_out_itineraries_mixing_currencies() {
+        let catalog = catalog(
+            "Lisbon,London,100 EUR,1\n\
+             London,Berlin,80 GBP,1\n\
+             Lisbon,Berlin,300 EUR,1\n",
+        );
+        assert_eq!(
+            routes(&catalog, "Lisbon", "Berlin", RouteOrder::Cheapest),
+            ["Lisbon -> Berlin (300.00 EUR)"]
+        );
+    }
+}
@@ -0,0 +1,10 @@
+pub mod catalog;
+pub mod clock;
+pub mod config;
+pub mod file;
+pub mod itinerary;
+pub mod money;
+pub mod pricing;
+pub mod query;
+pub mod shell;
+pub mod users;
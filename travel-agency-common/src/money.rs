@@ -0,0 +1,471 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{cmp::Ordering, convert::TryFrom, fmt, str::FromStr};
+
+/// Number of minor units in a major unit, every currency is assumed to have cents.
+const MINOR_PER_MAJOR: i64 = 100;
+/// Exchange rates are kept with six decimal places.
+const RATE_SCALE: i64 = 1_000_000;
+
+/// Three letter ISO 4217 code, e.g. `EUR`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const EUR: Currency = Currency(*b"EUR");
+    pub const USD: Currency = Currency(*b"USD");
+    pub const GBP: Currency = Currency(*b"GBP");
+
+    pub fn as_str(&self) -> &str {
+        // only ever built from ASCII letters
+        std::str::from_utf8(&self.0).unwrap()
+    }
+}
+
+/// Currency of amounts written without one.
+impl Default for Currency {
+    fn default() -> Self {
+        Currency::EUR
+    }
+}
+
+impl FromStr for Currency {
+    type Err = MoneyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            &[a, b, c] if s.bytes().all(|c| c.is_ascii_uppercase()) => Ok(Currency([a, b, c])),
+            _ => Err(MoneyError::InvalidCurrency(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MoneyError {
+    Overflow,
+    CurrencyMismatch(Currency, Currency),
+    InvalidAmount(String),
+    InvalidCurrency(String),
+    InvalidRate(String),
+}
+
+impl fmt::Display for MoneyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoneyError::Overflow => write!(f, "amount out of range"),
+            MoneyError::CurrencyMismatch(a, b) => {
+                write!(f, "cannot mix amounts in {} and {}", a, b)
+            }
+            MoneyError::InvalidAmount(amount) => write!(
+                f,
+                "invalid amount \"{}\", expected e.g. 450 or 450.50 EUR",
+                amount
+            ),
+            MoneyError::InvalidCurrency(currency) => write!(
+                f,
+                "invalid currency \"{}\", expected a code like EUR",
+                currency
+            ),
+            MoneyError::InvalidRate(rate) => write!(
+                f,
+                "invalid exchange rate \"{}\", expected a positive number like 0.85",
+                rate
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MoneyError {}
+
+/// An amount in the minor units of its currency, e.g. cents.
+///
+/// Arithmetic is checked, amounts never silently overflow or mix currencies.
+/// Amounts of different currencies are not comparable.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Money {
+    minor: i64,
+    currency: Currency,
+}
+
+impl Money {
+    pub const fn new(minor: i64, currency: Currency) -> Self {
+        Self { minor, currency }
+    }
+
+    pub const fn zero(currency: Currency) -> Self {
+        Self::new(0, currency)
+    }
+
+    pub fn from_major(major: i64, currency: Currency) -> Result<Self, MoneyError> {
+        major
+            .checked_mul(MINOR_PER_MAJOR)
+            .map(|minor| Self::new(minor, currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    pub fn minor(&self) -> i64 {
+        self.minor
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.minor < 0
+    }
+
+    pub fn checked_add(self, other: Money) -> Result<Money, MoneyError> {
+        self.same_currency(other)?;
+        self.minor
+            .checked_add(other.minor)
+            .map(|minor| Self::new(minor, self.currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    pub fn checked_sub(self, other: Money) -> Result<Money, MoneyError> {
+        self.same_currency(other)?;
+        self.minor
+            .checked_sub(other.minor)
+            .map(|minor| Self::new(minor, self.currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    pub fn checked_mul(self, factor: i64) -> Result<Money, MoneyError> {
+        self.minor
+            .checked_mul(factor)
+            .map(|minor| Self::new(minor, self.currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    pub fn checked_neg(self) -> Result<Money, MoneyError> {
+        self.minor
+            .checked_neg()
+            .map(|minor| Self::new(minor, self.currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    /// `percent`% of the amount, rounded half up to the nearest minor unit.
+    pub fn percent(self, percent: i64) -> Result<Money, MoneyError> {
+        let scaled = self.checked_mul(percent)?;
+        scaled
+            .minor
+            .checked_add(50)
+            .map(|minor| Self::new(minor.div_euclid(100), self.currency))
+            .ok_or(MoneyError::Overflow)
+    }
+
+    /// The amount in `currency` at `rate`, rounded half up to the nearest minor unit.
+    pub fn convert(self, currency: Currency, rate: Rate) -> Result<Money, MoneyError> {
+        let scaled = i128::from(self.minor) * i128::from(rate.scaled) + i128::from(RATE_SCALE / 2);
+        i64::try_from(scaled.div_euclid(i128::from(RATE_SCALE)))
+            .map(|minor| Self::new(minor, currency))
+            .map_err(|_| MoneyError::Overflow)
+    }
+
+    /// Add up `amounts`, all of them in `currency`.
+    pub fn sum<I>(currency: Currency, amounts: I) -> Result<Money, MoneyError>
+    where
+        I: IntoIterator<Item = Money>,
+    {
+        amounts
+            .into_iter()
+            .try_fold(Money::zero(currency), Money::checked_add)
+    }
+
+    fn same_currency(self, other: Money) -> Result<(), MoneyError> {
+        if self.currency == other.currency {
+            Ok(())
+        } else {
+            Err(MoneyError::CurrencyMismatch(self.currency, other.currency))
+        }
+    }
+}
+
+impl PartialOrd for Money {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self.currency == other.currency {
+            Some(self.minor.cmp(&other.minor))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.is_negative() { "-" } else { "" };
+        let minor = self.minor.unsigned_abs();
+        let per_major = MINOR_PER_MAJOR as u64;
+        write!(
+            f,
+            "{}{}.{:02} {}",
+            sign,
+            minor / per_major,
+            minor % per_major,
+            self.currency
+        )
+    }
+}
+
+impl fmt::Debug for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Parses `450`, `450.5` or `-450.50 EUR`, amounts without a currency are in the default one.
+impl FromStr for Money {
+    type Err = MoneyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (amount, currency) = match s.split_once(' ') {
+            Some((amount, currency)) => (amount, currency.trim().parse()?),
+            None => (s, Currency::default()),
+        };
+        let invalid = || MoneyError::InvalidAmount(s.to_string());
+        let (negative, digits) = match amount.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, amount),
+        };
+        let (major, cents) = match digits.split_once('.') {
+            Some((major, cents)) => (major, cents),
+            None => (digits, ""),
+        };
+        let all_digits = |s: &str| s.bytes().all(|c| c.is_ascii_digit());
+        if major.is_empty() || !all_digits(major) || cents.len() > 2 || !all_digits(cents) {
+            return Err(invalid());
+        }
+        let major: i64 = major.parse().map_err(|_| MoneyError::Overflow)?;
+        // "5" after the point is 50 cents
+        let cents: i64 = format!("{:0<2}", cents).parse().map_err(|_| invalid())?;
+        let money = Money::from_major(major, currency)?.checked_add(Money::new(cents, currency))?;
+        if negative {
+            money.checked_neg()
+        } else {
+            Ok(money)
+        }
+    }
+}
+
+/// Written like its display, e.g. `"217.30 EUR"`.
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let money = String::deserialize(deserializer)?;
+        money.parse().map_err(de::Error::custom)
+    }
+}
+
+/// How many units of one currency a unit of another one buys, e.g. `0.85` from EUR to GBP.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rate {
+    /// The rate times `RATE_SCALE`, always positive.
+    scaled: i64,
+}
+
+impl Rate {
+    pub const ONE: Rate = Rate { scaled: RATE_SCALE };
+
+    /// The rate to convert back, rounded to six decimal places.
+    pub fn inverse(self) -> Rate {
+        let squared = i128::from(RATE_SCALE) * i128::from(RATE_SCALE);
+        let scaled = (squared + i128::from(self.scaled) / 2) / i128::from(self.scaled);
+        // rates are at least one millionth so the inverse fits, very large rates round to zero
+        Rate {
+            scaled: i64::try_from(scaled).unwrap_or(i64::MAX).max(1),
+        }
+    }
+}
+
+impl FromStr for Rate {
+    type Err = MoneyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || MoneyError::InvalidRate(s.to_string());
+        let s = s.trim();
+        let (units, decimals) = match s.split_once('.') {
+            Some((units, decimals)) => (units, decimals),
+            None => (s, ""),
+        };
+        let all_digits = |s: &str| s.bytes().all(|c| c.is_ascii_digit());
+        if units.is_empty() || !all_digits(units) || decimals.len() > 6 || !all_digits(decimals) {
+            return Err(invalid());
+        }
+        let units: i64 = units.parse().map_err(|_| invalid())?;
+        let decimals: i64 = format!("{:0<6}", decimals).parse().map_err(|_| invalid())?;
+        let scaled = units
+            .checked_mul(RATE_SCALE)
+            .and_then(|scaled| scaled.checked_add(decimals))
+            .ok_or_else(invalid)?;
+        if scaled == 0 {
+            return Err(invalid());
+        }
+        Ok(Rate { scaled })
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let decimals = format!("{:06}", self.scaled % RATE_SCALE);
+        let decimals = decimals.trim_end_matches('0');
+        write!(f, "{}", self.scaled / RATE_SCALE)?;
+        if !decimals.is_empty() {
+            write!(f, ".{}", decimals)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eur(s: &str) -> Money {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_amounts_and_currencies() {
+        assert_eq!(eur("450.5 EUR"), Money::new(45050, Currency::EUR));
+        assert_eq!(eur("450.50 EUR"), Money::new(45050, Currency::EUR));
+        assert_eq!(eur("450"), Money::new(45000, Currency::EUR));
+        assert_eq!(eur(" -0.05 USD "), Money::new(-5, Currency::USD));
+        assert_eq!(eur("217.3 GBP").to_string(), "217.30 GBP");
+        assert_eq!(eur("-1.05").to_string(), "-1.05 EUR");
+    }
+
+    #[test]
+    fn rejects_invalid_amounts() {
+        for amount in ["", "abc", "4.505", ".5", "-", "1,5", "+1", "1.-5"] {
+            assert_eq!(
+                amount.parse::<Money>(),
+                Err(MoneyError::InvalidAmount(amount.trim().to_string())),
+                "{}",
+                amount
+            );
+        }
+        assert_eq!(
+            "1 eur".parse::<Money>(),
+            Err(MoneyError::InvalidCurrency("eur".to_string()))
+        );
+        assert_eq!(
+            "1 EURO".parse::<Money>(),
+            Err(MoneyError::InvalidCurrency("EURO".to_string()))
+        );
+        assert_eq!(
+            "99999999999999999999".parse::<Money>(),
+            Err(MoneyError::Overflow)
+        );
+        assert_eq!(
+            "92233720368547758.07".parse::<Money>(),
+            Ok(Money::new(i64::MAX, Currency::EUR))
+        );
+        assert_eq!(
+            "92233720368547758.08".parse::<Money>(),
+            Err(MoneyError::Overflow)
+        );
+    }
+
+    #[test]
+    fn arithmetic_overflows_are_errors() {
+        let max = Money::new(i64::MAX, Currency::EUR);
+        let min = Money::new(i64::MIN, Currency::EUR);
+        let cent = Money::new(1, Currency::EUR);
+        assert_eq!(max.checked_add(cent), Err(MoneyError::Overflow));
+        assert_eq!(min.checked_sub(cent), Err(MoneyError::Overflow));
+        assert_eq!(max.checked_mul(2), Err(MoneyError::Overflow));
+        assert_eq!(min.checked_neg(), Err(MoneyError::Overflow));
+        assert_eq!(max.percent(100), Err(MoneyError::Overflow));
+        assert_eq!(
+            Money::from_major(i64::MAX / 10, Currency::EUR),
+            Err(MoneyError::Overflow)
+        );
+        assert_eq!(
+            Money::sum(Currency::EUR, vec![max, cent]),
+            Err(MoneyError::Overflow)
+        );
+        assert_eq!(
+            max.convert(Currency::USD, "2".parse().unwrap()),
+            Err(MoneyError::Overflow)
+        );
+    }
+
+    #[test]
+    fn currencies_do_not_mix() {
+        let euros = eur("10 EUR");
+        let dollars = eur("10 USD");
+        let mismatch = Err(MoneyError::CurrencyMismatch(Currency::EUR, Currency::USD));
+        assert_eq!(euros.checked_add(dollars), mismatch);
+        assert_eq!(euros.checked_sub(dollars), mismatch);
+        assert_eq!(Money::sum(Currency::EUR, vec![euros, dollars]), mismatch);
+        assert_eq!(euros.partial_cmp(&dollars), None);
+        assert!(euros < eur("10.01 EUR"));
+    }
+
+    #[test]
+    fn rounds_half_up_to_the_cent() {
+        // half of 1.01 is 50.5 cents
+        assert_eq!(eur("1.01").percent(50), Ok(eur("0.51")));
+        assert_eq!(eur("0.05").percent(50), Ok(eur("0.03")));
+        assert_eq!(eur("0.05").percent(10), Ok(eur("0.01")));
+        assert_eq!(eur("0.04").percent(10), Ok(eur("0")));
+        assert_eq!(eur("-0.05").percent(50), Ok(eur("-0.02")));
+        let rate: Rate = "0.855".parse().unwrap();
+        assert_eq!(
+            eur("0.10").convert(Currency::GBP, rate),
+            Ok(eur("0.09 GBP"))
+        );
+        assert_eq!(
+            eur("1.00").convert(Currency::GBP, rate),
+            Ok(eur("0.86 GBP"))
+        );
+        assert_eq!(
+            eur("100").convert(Currency::USD, Rate::ONE),
+            Ok(eur("100 USD"))
+        );
+    }
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!("0.85".parse::<Rate>().unwrap().to_string(), "0.85");
+        assert_eq!("2".parse::<Rate>().unwrap().to_string(), "2");
+        assert_eq!("0.000001".parse::<Rate>().unwrap().to_string(), "0.000001");
+        for rate in ["0", "0.0", "-1", "1.0000001", "", "1e3", "one"] {
+            assert_eq!(
+                rate.parse::<Rate>(),
+                Err(MoneyError::InvalidRate(rate.to_string())),
+                "{}",
+                rate
+            );
+        }
+        assert_eq!("0.8".parse::<Rate>().unwrap().inverse().to_string(), "1.25");
+        assert_eq!(
+            "3".parse::<Rate>().unwrap().inverse().to_string(),
+            "0.333333"
+        );
+    }
+}
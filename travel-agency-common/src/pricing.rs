@@ -0,0 +1,85 @@
+use crate::{
+    itinerary::Itinerary,
+    money::{Currency, Money, MoneyError},
+};
+use std::fmt;
+
+/// Booking fee charged for every leg of an itinerary, in minor units of its currency.
+pub const FEE_PER_LEG: i64 = 500;
+/// Tax applied over the price and fees, in percent.
+pub const TAX_PERCENT: i64 = 6;
+
+#[derive(Clone, Debug)]
+pub struct QuoteLine {
+    pub itinerary: Itinerary,
+    pub price: Money,
+    pub fees: Money,
+    pub taxes: Money,
+    pub total: Money,
+}
+
+impl QuoteLine {
+    pub fn new(itinerary: &Itinerary) -> Result<Self, MoneyError> {
+        let price = itinerary.price()?;
+        let legs = itinerary.legs.len() as i64;
+        let fees = Money::new(FEE_PER_LEG, price.currency()).checked_mul(legs)?;
+        let taxes = price.checked_add(fees)?.percent(TAX_PERCENT)?;
+        Ok(Self {
+            itinerary: itinerary.clone(),
+            price,
+            fees,
+            taxes,
+            total: price.checked_add(fees)?.checked_add(taxes)?,
+        })
+    }
+}
+
+/// Price breakdown of a selection, what `buy` will charge.
+///
+/// Quoting fails when the amounts overflow or the itineraries are in different currencies.
+#[derive(Clone, Debug)]
+pub struct Quote {
+    pub lines: Vec<QuoteLine>,
+    pub price: Money,
+    pub fees: Money,
+    pub taxes: Money,
+    pub total: Money,
+}
+
+impl Quote {
+    pub fn new(itineraries: &[Itinerary]) -> Result<Self, MoneyError> {
+        let lines = itineraries
+            .iter()
+            .map(QuoteLine::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        let currency = lines
+            .first()
+            .map_or(Currency::default(), |line| line.total.currency());
+        let sum = |amount: fn(&QuoteLine) -> Money| Money::sum(currency, lines.iter().map(amount));
+        Ok(Self {
+            price: sum(|line| line.price)?,
+            fees: sum(|line| line.fees)?,
+            taxes: sum(|line| line.taxes)?,
+            total: sum(|line| line.total)?,
+            lines,
+        })
+    }
+}
+
+impl fmt::Display for Quote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, line) in self.lines.iter().enumerate() {
+            writeln!(f, "{}: {}", i, line.itinerary)?;
+            writeln!(
+                f,
+                "   price {} + fees {} + taxes {} = {}",
+                line.price, line.fees, line.taxes, line.total
+            )?;
+        }
+        write!(
+            f,
+            "total: price {} + fees {} + taxes {} ({}%) = {}",
+            self.price, self.fees, self.taxes, TAX_PERCENT, self.total
+        )
+    }
+}
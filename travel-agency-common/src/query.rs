@@ -0,0 +1,249 @@
+use crate::{
+    catalog::Trip,
+    money::Money,
+    shell::{self, SplitError},
+};
+use std::{fmt, str::FromStr};
+
+const KEYS: &str = "from, to, max or sort";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    Price,
+    From,
+    To,
+}
+
+impl FromStr for SortKey {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "price" => Ok(SortKey::Price),
+            "from" => Ok(SortKey::From),
+            "to" => Ok(SortKey::To),
+            _ => Err(QueryError::InvalidSort(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum QueryError {
+    Empty,
+    Syntax(SplitError),
+    UnknownKey(String),
+    MissingValue(String),
+    DuplicateKey(String),
+    InvalidPrice(String),
+    InvalidSort(String),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::Empty => write!(f, "empty query, try: from:Lisbon to:Berlin max:500"),
+            QueryError::Syntax(err) => write!(f, "{} in query", err),
+            QueryError::UnknownKey(key) => {
+                write!(f, "unknown filter \"{}\" (expected {})", key, KEYS)
+            }
+            QueryError::MissingValue(key) => {
+                write!(f, "missing value for {}, e.g. {}:Lisbon", key, key)
+            }
+            QueryError::DuplicateKey(key) => write!(f, "{} was given more than once", key),
+            QueryError::InvalidPrice(value) => {
+                write!(
+                    f,
+                    "invalid max price \"{}\", expected e.g. 500 or \"500 EUR\"",
+                    value
+                )
+            }
+            QueryError::InvalidSort(value) => {
+                write!(f, "invalid sort \"{}\" (expected price, from or to)", value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// A parsed trip search.
+///
+/// Queries are whitespace separated `key:value` filters, e.g. `from:Lisbon to:Berlin max:500 sort:price`.
+/// Words without a key match trips that start or end in that city,
+/// values containing spaces must be quoted, e.g. `to:"New York"`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SearchQuery {
+    pub city: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub max_price: Option<Money>,
+    pub sort: Option<SortKey>,
+}
+
+impl SearchQuery {
+    pub fn matches(&self, trip: &Trip) -> bool {
+        self.city.as_ref().is_none_or(|city| trip.matches(city))
+            && self.from.as_ref().is_none_or(|from| &trip.from == from)
+            && self.to.as_ref().is_none_or(|to| &trip.to == to)
+            && self.max_price.is_none_or(|max| trip.price <= max)
+    }
+
+    /// Parse a query already split into words, quotes removed.
+    pub fn from_words<S: AsRef<str>>(words: &[S]) -> Result<Self, QueryError> {
+        let mut query = SearchQuery::default();
+        let mut cities = vec![];
+        for word in words {
+            let word = word.as_ref();
+            let (key, value) = match word.split_once(':') {
+                Some((key, value)) => (key, value),
+                None => {
+                    cities.push(word);
+                    continue;
+                }
+            };
+            if value.is_empty() {
+                return Err(QueryError::MissingValue(key.to_string()));
+            }
+            match key {
+                "from" => set(&mut query.from, key, value.to_string())?,
+                "to" => set(&mut query.to, key, value.to_string())?,
+                "max" => {
+                    let max = value
+                        .parse()
+                        .map_err(|_| QueryError::InvalidPrice(value.to_string()))?;
+                    set(&mut query.max_price, key, max)?
+                }
+                "sort" => set(&mut query.sort, key, value.parse()?)?,
+                _ => return Err(QueryError::UnknownKey(key.to_string())),
+            }
+        }
+        if !cities.is_empty() {
+            query.city = Some(cities.join(" "));
+        }
+        if query == SearchQuery::default() {
+            return Err(QueryError::Empty);
+        }
+        Ok(query)
+    }
+
+    pub fn sort(&self, trips: &mut [Trip]) {
+        match self.sort {
+            Some(SortKey::Price) => {
+                trips.sort_by_key(|trip| (trip.price.currency(), trip.price.minor()))
+            }
+            Some(SortKey::From) => trips.sort_by(|a, b| a.from.cmp(&b.from)),
+            Some(SortKey::To) => trips.sort_by(|a, b| a.to.cmp(&b.to)),
+            None => {}
+        }
+    }
+}
+
+impl FromStr for SearchQuery {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words = shell::split(s).map_err(QueryError::Syntax)?;
+        Self::from_words(&words)
+    }
+}
+
+fn set<T>(field: &mut Option<T>, key: &str, value: T) -> Result<(), QueryError> {
+    if field.is_some() {
+        return Err(QueryError::DuplicateKey(key.to_string()));
+    }
+    *field = Some(value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trip(from: &str, to: &str, price: &str) -> Trip {
+        Trip::new(from.to_string(), to.to_string(), price.parse().unwrap(), 1)
+    }
+
+    #[test]
+    fn parses_filters_and_cities() {
+        let query: SearchQuery = "New York from:Lisbon to:\"Los Angeles\" max:\"500 USD\" sort:to"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            query,
+            SearchQuery {
+                city: Some("New York".to_string()),
+                from: Some("Lisbon".to_string()),
+                to: Some("Los Angeles".to_string()),
+                max_price: Some("500 USD".parse().unwrap()),
+                sort: Some(SortKey::To),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_queries() {
+        for (query, err) in [
+            ("", QueryError::Empty),
+            (
+                "to:\"Berlin",
+                QueryError::Syntax(SplitError::UnterminatedQuote('"')),
+            ),
+            ("via:Paris", QueryError::UnknownKey("via".to_string())),
+            ("from:", QueryError::MissingValue("from".to_string())),
+            (
+                "to:Berlin to:Paris",
+                QueryError::DuplicateKey("to".to_string()),
+            ),
+            ("max:cheap", QueryError::InvalidPrice("cheap".to_string())),
+            ("max:5.005", QueryError::InvalidPrice("5.005".to_string())),
+            ("sort:date", QueryError::InvalidSort("date".to_string())),
+        ] {
+            assert_eq!(query.parse::<SearchQuery>(), Err(err), "{}", query);
+        }
+    }
+
+    #[test]
+    fn matches_every_filter() {
+        let query: SearchQuery = "London max:300".parse().unwrap();
+        assert!(query.matches(&trip("Lisbon", "London", "300")));
+        assert!(query.matches(&trip("London", "Berlin", "120")));
+        assert!(!query.matches(&trip("Lisbon", "London", "300.01")));
+        assert!(!query.matches(&trip("Lisbon", "Berlin", "100")));
+    }
+
+    #[test]
+    fn sorts_by_the_key_given() {
+        let mut trips = vec![
+            trip("Paris", "Berlin", "90 USD"),
+            trip("Lisbon", "Rome", "300"),
+            trip("Berlin", "London", "80 GBP"),
+            trip("Madrid", "Athens", "120"),
+        ];
+        let sorted = |trips: &mut [Trip], query: &str| {
+            let query: SearchQuery = query.parse().unwrap();
+            query.sort(trips);
+            trips
+                .iter()
+                .map(|trip| trip.from.clone())
+                .collect::<Vec<_>>()
+        };
+        // grouped by currency, cheapest first in each
+        assert_eq!(
+            sorted(&mut trips, "sort:price"),
+            ["Madrid", "Lisbon", "Berlin", "Paris"]
+        );
+        assert_eq!(
+            sorted(&mut trips, "sort:from"),
+            ["Berlin", "Lisbon", "Madrid", "Paris"]
+        );
+        assert_eq!(
+            sorted(&mut trips, "sort:to"),
+            ["Madrid", "Paris", "Berlin", "Lisbon"]
+        );
+        // without a sort the order is kept
+        assert_eq!(
+            sorted(&mut trips, "from:Lisbon"),
+            ["Madrid", "Paris", "Berlin", "Lisbon"]
+        );
+    }
+}
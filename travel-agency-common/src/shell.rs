@@ -0,0 +1,111 @@
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SplitError {
+    /// A `"` or `'` quote was opened and never closed.
+    UnterminatedQuote(char),
+    /// The line ends with a `\` escaping nothing.
+    TrailingBackslash,
+}
+
+impl fmt::Display for SplitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SplitError::UnterminatedQuote(quote) => write!(f, "unterminated {} quote", quote),
+            SplitError::TrailingBackslash => write!(f, "nothing to escape after \\"),
+        }
+    }
+}
+
+impl std::error::Error for SplitError {}
+
+/// Split a line into words the way a shell does.
+///
+/// Words are separated by any amount of whitespace. Double or single quotes keep
+/// whitespace in a word, e.g. `"New York"` or `to:'New York'`, and `""` is an empty word.
+/// Outside single quotes a `\` takes the next character literally.
+pub fn split(line: &str) -> Result<Vec<String>, SplitError> {
+    let mut words = vec![];
+    let mut word = String::new();
+    // a word may be empty when it was quoted
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), c) => word.push(c),
+            (_, '\\') => {
+                word.push(chars.next().ok_or(SplitError::TrailingBackslash)?);
+                in_word = true;
+            }
+            (Some(_), c) => word.push(c),
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if let Some(quote) = quote {
+        return Err(SplitError::UnterminatedQuote(quote));
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Vec<String> {
+        split(line).unwrap()
+    }
+
+    #[test]
+    fn splits_on_whitespace() {
+        assert_eq!(words("  search \t Lisbon  "), ["search", "Lisbon"]);
+        assert!(words("").is_empty());
+        assert!(words("   ").is_empty());
+    }
+
+    #[test]
+    fn quotes_keep_whitespace() {
+        assert_eq!(words(r#"search "New York""#), ["search", "New York"]);
+        assert_eq!(words("search 'New York'"), ["search", "New York"]);
+        assert_eq!(words(r#"search to:"New York""#), ["search", "to:New York"]);
+        assert_eq!(words(r#"a "" ''"#), ["a", "", ""]);
+        assert_eq!(words(r#""it's" 'say "hi"'"#), ["it's", r#"say "hi""#]);
+    }
+
+    #[test]
+    fn backslashes_escape_outside_single_quotes() {
+        assert_eq!(words(r"New\ York"), ["New York"]);
+        assert_eq!(words(r#""a \" b""#), [r#"a " b"#]);
+        assert_eq!(words(r"'a\b'"), [r"a\b"]);
+    }
+
+    #[test]
+    fn reports_unterminated_quotes() {
+        assert_eq!(
+            split(r#"search "New York"#),
+            Err(SplitError::UnterminatedQuote('"'))
+        );
+        assert_eq!(
+            split("search 'New York"),
+            Err(SplitError::UnterminatedQuote('\''))
+        );
+        assert_eq!(split(r"search \"), Err(SplitError::TrailingBackslash));
+    }
+}
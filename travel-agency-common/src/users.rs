@@ -0,0 +1,684 @@
+use crate::{clock::Clock, config, file};
+use pbkdf2::pbkdf2_hmac;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Command line flag used to pass the user store path.
+pub const USERS_FLAG: &str = "--users";
+/// Environment variable used when the flag is not present.
+pub const USERS_ENV: &str = "TRAVEL_AGENCY_USERS";
+/// User store used when neither the flag nor the variable are set.
+pub const DEFAULT_USERS: &str = "users.csv";
+
+const HEADER: [&str; 7] = [
+    "username",
+    "salt",
+    "hash",
+    "email",
+    "failed",
+    "locked_until",
+    "role",
+];
+/// Columns every line has, older stores have no email, lockout or role columns.
+const REQUIRED_FIELDS: usize = 3;
+const SALT_LEN: usize = 16;
+const USERNAME_LEN: (usize, usize) = (3, 32);
+const MIN_PASSWORD_LEN: usize = 8;
+/// PBKDF2-HMAC-SHA256 iterations, so that guessing passwords is slow.
+const HASH_ROUNDS: u32 = 600_000;
+const HASH_LEN: usize = 32;
+/// Failed logins in a row that lock an account.
+pub const MAX_FAILED_LOGINS: u32 = 5;
+/// Seconds an account stays locked.
+pub const LOCKOUT: u64 = 15 * 60;
+
+/// Who is logged in.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct User {
+    pub username: String,
+    /// Users from before registration asked for one have none.
+    pub email: Option<String>,
+    #[serde(default)]
+    pub role: Role,
+}
+
+/// What a user may do, customers buy trips and admins manage the agency.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    #[default]
+    Customer,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Customer => write!(f, "customer"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "customer" => Ok(Role::Customer),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("unknown role {}, expected customer or admin", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// The user does not exist or the password is wrong, the two are not told apart.
+    InvalidCredentials,
+    /// Too many failed logins, the account unlocks in `remaining` seconds.
+    Locked { remaining: u64 },
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::InvalidCredentials => write!(f, "Invalid credentials"),
+            AuthError::Locked { remaining } => write!(
+                f,
+                "Account locked after {} failed logins, try again in {} minutes",
+                MAX_FAILED_LOGINS,
+                remaining.div_ceil(60)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+#[derive(Debug)]
+pub enum RegistrationError {
+    InvalidUsername(String),
+    UsernameTaken(String),
+    WeakPassword(String),
+    InvalidEmail(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistrationError::InvalidUsername(username) => write!(
+                f,
+                "invalid username \"{}\", use {} to {} letters, digits, '.', '-' or '_'",
+                username, USERNAME_LEN.0, USERNAME_LEN.1
+            ),
+            RegistrationError::UsernameTaken(username) => {
+                write!(f, "username \"{}\" is already taken", username)
+            }
+            RegistrationError::WeakPassword(reason) => write!(f, "weak password: {}", reason),
+            RegistrationError::InvalidEmail(email) => {
+                write!(
+                    f,
+                    "invalid email \"{}\", expected e.g. name@example.com",
+                    email
+                )
+            }
+            RegistrationError::Io(err) => write!(f, "could not save the user store: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RegistrationError {}
+
+#[derive(Clone, Debug)]
+struct Credentials {
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+    email: Option<String>,
+    /// Failed logins since the last successful one.
+    failed: u32,
+    /// Seconds since the UNIX epoch.
+    locked_until: Option<u64>,
+    role: Role,
+}
+
+impl Credentials {
+    fn new(password: &str, email: Option<String>, role: Role) -> Self {
+        let mut salt = vec![0; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let hash = hash_password(&salt, password);
+        Self {
+            salt,
+            hash,
+            email,
+            failed: 0,
+            locked_until: None,
+            role,
+        }
+    }
+
+    fn user(&self, username: &str) -> User {
+        User {
+            username: username.to_string(),
+            email: self.email.clone(),
+            role: self.role,
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        let hash = hash_password(&self.salt, password);
+        // compare every byte, the time taken does not tell how much matched
+        hash.len() == self.hash.len()
+            && hash
+                .iter()
+                .zip(&self.hash)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+/// The users that can log in, with salted PBKDF2-HMAC-SHA256 hashes of their passwords.
+///
+/// User stores are CSV files with one `username,salt,hash,email,failed,locked_until,role` line
+/// per user, salts and hashes written in hexadecimal. The email and the lockout columns,
+/// failed logins in a row and when the account unlocks, may be left out,
+/// and so may the role, `customer` or `admin`, which defaults to `customer`.
+/// Blank lines and lines starting with `#` are ignored,
+/// and an optional header with those column names may come before the first user.
+#[derive(Debug, Default)]
+pub struct UserStore {
+    users: HashMap<String, Credentials>,
+    path: Option<PathBuf>,
+}
+
+impl UserStore {
+    /// Open the store given by `--users <path>`, the `TRAVEL_AGENCY_USERS` variable
+    /// or [`DEFAULT_USERS`], in that order.
+    pub fn from_env() -> io::Result<Self> {
+        Self::open(config::path_arg(USERS_FLAG, USERS_ENV, DEFAULT_USERS))
+    }
+
+    /// Open the store at `path`, a missing file starts with no users.
+    ///
+    /// Customers register themselves, administrators are added with [`UserStore::add_admin`].
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let users = match fs::read_to_string(path) {
+            Ok(contents) => parse_users(&contents)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            users,
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    pub fn contains(&self, username: &str) -> bool {
+        self.users.contains_key(username)
+    }
+
+    /// Every user, by username.
+    pub fn users(&self) -> Vec<User> {
+        let mut users: Vec<_> = self
+            .users
+            .iter()
+            .map(|(username, credentials)| credentials.user(username))
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+
+    /// Check the password of `username`, counting failed logins.
+    ///
+    /// After [`MAX_FAILED_LOGINS`] failures in a row the account is locked for [`LOCKOUT`] seconds,
+    /// as told by `clock`.
+    pub fn authenticate(
+        &mut self,
+        username: &str,
+        password: &str,
+        clock: &dyn Clock,
+    ) -> Result<User, AuthError> {
+        let now = clock.now();
+        let credentials = match self.users.get_mut(username) {
+            Some(credentials) => credentials,
+            None => {
+                // hash anyway, so that unknown users take as long as wrong passwords
+                Credentials::new(password, None, Role::Customer);
+                return Err(AuthError::InvalidCredentials);
+            }
+        };
+        if let Some(until) = credentials.locked_until {
+            if until > now {
+                return Err(AuthError::Locked {
+                    remaining: until - now,
+                });
+            }
+            credentials.locked_until = None;
+            credentials.failed = 0;
+        }
+        let failed = credentials.failed;
+        let result = if credentials.verify(password) {
+            credentials.failed = 0;
+            Ok(credentials.user(username))
+        } else {
+            credentials.failed += 1;
+            if credentials.failed >= MAX_FAILED_LOGINS {
+                credentials.locked_until = Some(now + LOCKOUT);
+                Err(AuthError::Locked { remaining: LOCKOUT })
+            } else {
+                Err(AuthError::InvalidCredentials)
+            }
+        };
+        if credentials.failed != failed {
+            // the count still holds for this process if it cannot be saved
+            let _ = self.save();
+        }
+        result
+    }
+
+    /// Create a new user and save the store.
+    ///
+    /// Passwords need at least 8 characters, with letters and digits, and cannot be the username.
+    pub fn register(
+        &mut self,
+        username: &str,
+        password: &str,
+        email: &str,
+    ) -> Result<User, RegistrationError> {
+        if !valid_username(username) {
+            return Err(RegistrationError::InvalidUsername(username.to_string()));
+        }
+        if self.contains(username) {
+            return Err(RegistrationError::UsernameTaken(username.to_string()));
+        }
+        check_password(username, password).map_err(RegistrationError::WeakPassword)?;
+        if !valid_email(email) {
+            return Err(RegistrationError::InvalidEmail(email.to_string()));
+        }
+        let credentials = Credentials::new(password, Some(email.to_string()), Role::Customer);
+        self.insert(username, credentials)
+            .map_err(RegistrationError::Io)
+    }
+
+    /// Make `username` an administrator with `password` and save the store.
+    ///
+    /// An existing user keeps their email and is unlocked, the password follows
+    /// the same rules as [`UserStore::register`].
+    pub fn add_admin(&mut self, username: &str, password: &str) -> Result<User, RegistrationError> {
+        if !valid_username(username) {
+            return Err(RegistrationError::InvalidUsername(username.to_string()));
+        }
+        check_password(username, password).map_err(RegistrationError::WeakPassword)?;
+        let email = self
+            .users
+            .get(username)
+            .and_then(|credentials| credentials.email.clone());
+        self.insert(username, Credentials::new(password, email, Role::Admin))
+            .map_err(RegistrationError::Io)
+    }
+
+    fn insert(&mut self, username: &str, credentials: Credentials) -> io::Result<User> {
+        let user = credentials.user(username);
+        let previous = self.users.insert(username.to_string(), credentials);
+        if let Err(err) = self.save() {
+            match previous {
+                Some(previous) => self.users.insert(username.to_string(), previous),
+                None => self.users.remove(username),
+            };
+            return Err(err);
+        }
+        Ok(user)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut users: Vec<_> = self.users.iter().collect();
+        users.sort_by_key(|(username, _)| *username);
+        let mut contents = format!("{}\n", HEADER.join(","));
+        for (username, credentials) in users {
+            let locked_until = credentials
+                .locked_until
+                .map_or(String::new(), |until| until.to_string());
+            contents.push_str(&format!(
+                "{},{},{},{},{},{},{}\n",
+                username,
+                to_hex(&credentials.salt),
+                to_hex(&credentials.hash),
+                credentials.email.as_deref().unwrap_or(""),
+                credentials.failed,
+                locked_until,
+                credentials.role
+            ));
+        }
+        file::write_atomic(path, contents)
+    }
+}
+
+fn hash_password(salt: &[u8], password: &str) -> Vec<u8> {
+    let mut hash = vec![0; HASH_LEN];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, HASH_ROUNDS, &mut hash);
+    hash
+}
+
+fn valid_username(username: &str) -> bool {
+    let (min, max) = USERNAME_LEN;
+    (min..=max).contains(&username.len())
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ['.', '-', '_'].contains(&c))
+}
+
+fn check_password(username: &str, password: &str) -> Result<(), String> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(format!("use at least {} characters", MIN_PASSWORD_LEN));
+    }
+    if !password.chars().any(char::is_alphabetic) || !password.chars().any(|c| c.is_ascii_digit()) {
+        return Err("use both letters and digits".to_string());
+    }
+    if password.eq_ignore_ascii_case(username) {
+        return Err("cannot be the username".to_string());
+    }
+    Ok(())
+}
+
+/// A loose check, one `@` with something before it and a dotted domain after it.
+fn valid_email(email: &str) -> bool {
+    let (local, domain) = match email.split_once('@') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let allowed = |part: &str| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| !c.is_whitespace() && !c.is_control() && c != ',' && c != '@')
+    };
+    allowed(local)
+        && allowed(domain)
+        && domain.split('.').all(|label| !label.is_empty())
+        && domain.contains('.')
+}
+
+fn parse_users(contents: &str) -> io::Result<HashMap<String, Credentials>> {
+    let invalid = |line: usize, message: &str| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid user store, line {}: {}", line, message),
+        )
+    };
+    let mut users = HashMap::new();
+    // the header can only come before the first user
+    let mut first = true;
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<_> = line.split(',').map(str::trim).collect();
+        // older stores have a header with fewer columns
+        let header = first && fields.len() <= HEADER.len() && fields == HEADER[..fields.len()];
+        first = false;
+        if header {
+            continue;
+        }
+        if !(REQUIRED_FIELDS..=HEADER.len()).contains(&fields.len()) {
+            return Err(invalid(
+                i + 1,
+                "expected username,salt,hash,email,failed,locked_until,role",
+            ));
+        }
+        let (salt, hash) = match (from_hex(fields[1]), from_hex(fields[2])) {
+            (Some(salt), Some(hash)) => (salt, hash),
+            _ => return Err(invalid(i + 1, "salt and hash must be hexadecimal")),
+        };
+        let optional = |i: usize| fields.get(i).copied().filter(|field| !field.is_empty());
+        let email = optional(3).map(str::to_string);
+        let failed = match optional(4).map(str::parse).transpose() {
+            Ok(failed) => failed.unwrap_or(0),
+            Err(_) => return Err(invalid(i + 1, "failed logins must be a number")),
+        };
+        let locked_until = match optional(5).map(str::parse).transpose() {
+            Ok(locked_until) => locked_until,
+            Err(_) => return Err(invalid(i + 1, "locked_until must be a UNIX time")),
+        };
+        let role = match optional(6).map(str::parse::<Role>).transpose() {
+            Ok(role) => role.unwrap_or_default(),
+            Err(err) => return Err(invalid(i + 1, &err)),
+        };
+        users.insert(
+            fields[0].to_string(),
+            Credentials {
+                salt,
+                hash,
+                email,
+                failed,
+                locked_until,
+                role,
+            },
+        );
+    }
+    Ok(users)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            // only ASCII digits, so the pair is valid UTF-8
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    const USERNAME: &str = "traveller";
+    const PASSWORD: &str = "passw0rd";
+
+    /// A clock that only moves when told to.
+    #[derive(Default)]
+    struct FakeClock(Cell<u64>);
+
+    impl FakeClock {
+        fn advance(&self, seconds: u64) {
+            self.0.set(self.0.get() + seconds);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    fn store() -> UserStore {
+        let mut users = UserStore::default();
+        users
+            .register(USERNAME, PASSWORD, "traveller@example.com")
+            .unwrap();
+        users
+    }
+
+    #[test]
+    fn registered_users_can_log_in() {
+        let mut users = store();
+        let user = users
+            .authenticate(USERNAME, PASSWORD, &FakeClock::default())
+            .unwrap();
+        assert_eq!(user.email.as_deref(), Some("traveller@example.com"));
+        assert_eq!(user.role, Role::Customer);
+    }
+
+    #[test]
+    fn usernames_are_taken_once() {
+        let mut users = store();
+        assert!(matches!(
+            users.register(USERNAME, "an0therpass", "other@example.com"),
+            Err(RegistrationError::UsernameTaken(username)) if username == USERNAME
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_usernames() {
+        let mut users = UserStore::default();
+        let long = "a".repeat(USERNAME_LEN.1 + 1);
+        for username in ["ab", long.as_str(), "has space", "comma,name", "é-user", ""] {
+            assert!(
+                matches!(
+                    users.register(username, PASSWORD, "traveller@example.com"),
+                    Err(RegistrationError::InvalidUsername(_))
+                ),
+                "{}",
+                username
+            );
+        }
+        assert!(users
+            .register("jane.doe-1_x", PASSWORD, "jane@example.com")
+            .is_ok());
+    }
+
+    #[test]
+    fn rejects_weak_passwords() {
+        let mut users = UserStore::default();
+        for (password, reason) in [
+            ("sh0rt", "use at least 8 characters"),
+            ("lettersonly", "use both letters and digits"),
+            ("12345678", "use both letters and digits"),
+            ("TRAVELLER1", "cannot be the username"),
+        ] {
+            match users.register("traveller1", password, "traveller@example.com") {
+                Err(RegistrationError::WeakPassword(err)) => {
+                    assert_eq!(err, reason, "{}", password)
+                }
+                other => panic!("{}: {:?}", password, other),
+            }
+        }
+        assert!(users.users().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_emails() {
+        let mut users = UserStore::default();
+        for email in [
+            "",
+            "traveller",
+            "@example.com",
+            "traveller@",
+            "traveller@example",
+            "traveller@example..com",
+            "a@b@example.com",
+            "trav eller@example.com",
+            "traveller@example.com,admin",
+        ] {
+            assert!(
+                matches!(
+                    users.register(USERNAME, PASSWORD, email),
+                    Err(RegistrationError::InvalidEmail(_))
+                ),
+                "{}",
+                email
+            );
+        }
+    }
+
+    #[test]
+    fn parses_stores_with_a_header_after_comments() {
+        let users = parse_users(
+            "# users of the agency\n\
+             username,salt,hash\n\
+             traveller,00ff,a0b1\n\
+             boss,01,02,boss@example.com,0,,admin\n",
+        )
+        .unwrap();
+        assert_eq!(users["traveller"].salt, [0x00, 0xff]);
+        assert_eq!(users["traveller"].role, Role::Customer);
+        assert_eq!(users["boss"].role, Role::Admin);
+        let err = parse_users("traveller,00,00\nusername,salt,hash\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid user store, line 2: salt and hash must be hexadecimal"
+        );
+    }
+
+    #[test]
+    fn failed_logins_lock_the_account() {
+        let clock = FakeClock::default();
+        let mut users = store();
+        for _ in 1..MAX_FAILED_LOGINS {
+            assert_eq!(
+                users.authenticate(USERNAME, "wr0ngpass", &clock),
+                Err(AuthError::InvalidCredentials)
+            );
+        }
+        assert_eq!(
+            users.authenticate(USERNAME, "wr0ngpass", &clock),
+            Err(AuthError::Locked { remaining: LOCKOUT })
+        );
+        clock.advance(60);
+        assert_eq!(
+            users.authenticate(USERNAME, PASSWORD, &clock),
+            Err(AuthError::Locked {
+                remaining: LOCKOUT - 60
+            })
+        );
+    }
+
+    #[test]
+    fn locked_accounts_unlock_after_the_lockout() {
+        let clock = FakeClock::default();
+        let mut users = store();
+        for _ in 0..MAX_FAILED_LOGINS {
+            assert!(users.authenticate(USERNAME, "wr0ngpass", &clock).is_err());
+        }
+        clock.advance(LOCKOUT);
+        assert_eq!(
+            users
+                .authenticate(USERNAME, PASSWORD, &clock)
+                .unwrap()
+                .username,
+            USERNAME
+        );
+        // the count starts over once unlocked
+        assert_eq!(
+            users.authenticate(USERNAME, "wr0ngpass", &clock),
+            Err(AuthError::InvalidCredentials)
+        );
+    }
+
+    #[test]
+    fn successful_logins_reset_the_failed_count() {
+        let clock = FakeClock::default();
+        let mut users = store();
+        for _ in 1..MAX_FAILED_LOGINS {
+            assert!(users.authenticate(USERNAME, "wr0ngpass", &clock).is_err());
+        }
+        assert!(users.authenticate(USERNAME, PASSWORD, &clock).is_ok());
+        assert_eq!(
+            users.authenticate(USERNAME, "wr0ngpass", &clock),
+            Err(AuthError::InvalidCredentials)
+        );
+    }
+}
@@ -1,12 +1,18 @@
 use std::{
     collections::HashMap,
     io::{stdin, stdout, Write},
-    thread, usize,
+    process, thread, usize,
 };
 
 use session_types::{
     offer, session_channel, Branch, Chan, Choose, Eps, HasDual, Offer, Rec, Recv, Send, Var, Z,
 };
+use travel_agency_common::{
+    catalog::{Catalog, Trip},
+    clock::SystemClock,
+    query::SearchQuery,
+    users::UserStore,
+};
 
 macro_rules! offer_chain {
     ($ty:ty) => {
@@ -38,32 +44,6 @@ impl LoginError {
     }
 }
 
-#[derive(Debug, Clone)]
-struct Trip {
-    from: String,
-    to: String,
-}
-
-impl Trip {
-    fn new(from: String, to: String) -> Self {
-        Self { from, to }
-    }
-
-    fn mock() -> Vec<Self> {
-        let mut res = vec![];
-        res.push(Self::new("Lisbon".to_string(), "Berlin".to_string()));
-        res.push(Self::new("Lisbon".to_string(), "London".to_string()));
-        res.push(Self::new("Beijing".to_string(), "Tokyo".to_string()));
-        res.push(Self::new("Amsterdam".to_string(), "London".to_string()));
-        res.push(Self::new("London".to_string(), "Berlin".to_string()));
-        res
-    }
-
-    fn matches(&self, location: &str) -> bool {
-        self.from == location || self.to == location
-    }
-}
-
 #[derive(Debug)]
 struct Search(String);
 #[derive(Debug)]
@@ -109,31 +89,52 @@ type AgencyServer = Recv<LoginDetails, Choose<PostLogin, Send<LoginError, Eps>>>
 type AgencyClient = <AgencyServer as HasDual>::Dual;
 
 fn main() {
+    let catalog = match Catalog::from_env() {
+        Ok(catalog) => catalog,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    let users = match UserStore::from_env() {
+        Ok(users) => users,
+        Err(err) => {
+            eprintln!("could not open the user store: {}", err);
+            process::exit(1);
+        }
+    };
     let (server_chan, client_chan): (Chan<(), AgencyServer>, Chan<(), AgencyClient>) =
         session_channel();
-    let server_thread = thread::spawn(move || agency_server(server_chan));
+    let server_thread = thread::spawn(move || agency_server(server_chan, catalog, users));
     let client_thread = thread::spawn(move || agency_client(client_chan));
     let _ = (server_thread.join(), client_thread.join());
 }
 
-fn agency_server(c: Chan<(), AgencyServer>) {
-    authentication(c);
+fn agency_server(c: Chan<(), AgencyServer>, catalog: Catalog, mut users: UserStore) {
+    authentication(c, catalog, &mut users);
 }
 
-fn authentication(c: Chan<(), AgencyServer>) {
+fn authentication(c: Chan<(), AgencyServer>, catalog: Catalog, users: &mut UserStore) {
     let (c, login_details) = c.recv();
-    if login_details.username == "client" && login_details.password == "client" {
-        let c = c.sel1();
-        post_authentication(c);
-    } else {
-        c.sel2()
-            .send(LoginError::new("failed authentication".to_string()))
-            .close();
+    match users.authenticate(
+        &login_details.username,
+        &login_details.password,
+        &SystemClock,
+    ) {
+        Ok(user) => {
+            println!("{} logged in", user.username);
+            let c = c.sel1();
+            post_authentication(c, catalog);
+        }
+        Err(err) => {
+            c.sel2()
+                .send(LoginError::new(format!("failed authentication: {}", err)))
+                .close();
+        }
     }
 }
 
-fn post_authentication(c: Chan<(), PostLogin>) {
-    let trips = Trip::mock();
+fn post_authentication(c: Chan<(), PostLogin>, catalog: Catalog) {
     let mut c = c.enter();
     // the offer! macro does not work in loops
     loop {
@@ -142,14 +143,14 @@ fn post_authentication(c: Chan<(), PostLogin>) {
                 let (c, res) = c.recv();
                 println!("{:?}", res);
 
-                c.send(SearchResult(
-                    trips
-                        .iter()
-                        .filter(|trip| trip.matches(&res.0))
-                        .cloned()
-                        .collect(),
-                ))
-                .zero()
+                let trips = match res.0.parse::<SearchQuery>() {
+                    Ok(query) => catalog.search(&query),
+                    Err(err) => {
+                        println!("invalid search: {}", err);
+                        vec![]
+                    }
+                };
+                c.send(SearchResult(trips)).zero()
             }
             Branch::Right(c) => match c.offer() {
                 Branch::Left(c) => {
@@ -0,0 +1,56 @@
+//! Lists the transitions of each state of the session automaton for the REPL,
+//! so that `help` and tab completion always follow `src/agency.rs`.
+
+use std::{env, fmt::Write, fs, path::Path};
+use syn::{FnArg, Item, TraitItem};
+
+const AUTOMATON: &str = "src/agency.rs";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", AUTOMATON);
+    let source = fs::read_to_string(AUTOMATON).expect("could not read the session automaton");
+    let file = syn::parse_file(&source).expect("could not parse the session automaton");
+    let items = file
+        .items
+        .iter()
+        .find_map(|item| match item {
+            Item::Mod(module)
+                if module
+                    .attrs
+                    .iter()
+                    .any(|attr| attr.path().is_ident("typestate")) =>
+            {
+                module.content.as_ref().map(|(_, items)| items)
+            }
+            _ => None,
+        })
+        .expect("no #[typestate] module in the session automaton");
+    let mut table = String::from("const TRANSITIONS: &[(&str, &[&str])] = &[\n");
+    // each state is a trait whose methods taking `self` are its transitions
+    for item in items {
+        if let Item::Trait(state) = item {
+            let transitions: Vec<_> = state
+                .items
+                .iter()
+                .filter_map(|item| match item {
+                    TraitItem::Fn(f)
+                        if matches!(f.sig.inputs.first(), Some(FnArg::Receiver(_))) =>
+                    {
+                        Some(format!("{:?}", f.sig.ident.to_string()))
+                    }
+                    _ => None,
+                })
+                .collect();
+            writeln!(
+                table,
+                "    ({:?}, &[{}]),",
+                state.ident.to_string(),
+                transitions.join(", ")
+            )
+            .unwrap();
+        }
+    }
+    table.push_str("];\n");
+    let out = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out).join("transitions.rs"), table).unwrap();
+}
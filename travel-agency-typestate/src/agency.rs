@@ -1,69 +1,389 @@
-use crate::bank::bank_api;
 use crate::bank::bank_api::{
-    AccountValidationState as BankAccountValidationState, FinishState as BankFinishState,
-    ValidState as BankValidState,
+    AccountValidationState as BankAccountValidationState, AuthorizedState as BankAuthorizedState,
+    ChallengeState as BankChallengeState, ErrorState as BankErrorState,
+    FinishState as BankFinishState, ReviewState as BankReviewState,
+    RiskCheckState as BankRiskCheckState, ValidState as BankValidState,
 };
-use crate::Trip;
+use crate::bank::{bank_api, Bank, RiskReason};
 use agency_api::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    ops::ControlFlow,
+    path::PathBuf,
+    process,
+    rc::Rc,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use travel_agency_common::{
+    catalog::{Catalog, ReserveError, Trip},
+    clock::{Clock, SystemClock},
+    itinerary::{Itinerary, RouteOrder},
+    money::{Money, MoneyError},
+    pricing::Quote,
+    query::SearchQuery,
+    users::{Role, User, UserStore},
+};
 use typestate::typestate;
-use std::convert::TryInto;
+
+const AGENCY_ACCOUNT: &str = "travel_agency";
+/// Seconds the token the agency refunds with stays valid.
+const REFUND_TOKEN_TTL: u64 = 60;
+/// Seconds a logged in session may stay idle before it expires.
+pub const IDLE_TIMEOUT: u64 = 15 * 60;
+
+/// What every session shares, whoever is logged in.
+pub struct Agency {
+    pub catalog: RefCell<Catalog>,
+    pub bank: Rc<RefCell<Bank>>,
+    pub users: RefCell<UserStore>,
+    /// Carts saved on logout, restored the next time their user logs in.
+    pub(crate) carts: RefCell<HashMap<String, SavedCart>>,
+    /// Where the carts are kept between runs, see [`Agency::open_carts`].
+    pub(crate) carts_path: Option<PathBuf>,
+    /// Payments sent to manual review, until their user carries on with the purchase.
+    ///
+    /// Like the funds the bank holds, they only live as long as the process.
+    pub(crate) reviews: RefCell<Reviews>,
+    pub clock: Rc<dyn Clock>,
+    /// Seconds a session may stay idle, see [`IDLE_TIMEOUT`].
+    pub idle_timeout: u64,
+}
+
+impl Agency {
+    pub fn new(catalog: Catalog, bank: Bank, users: UserStore) -> Self {
+        Self {
+            catalog: RefCell::new(catalog),
+            bank: Rc::new(RefCell::new(bank)),
+            users: RefCell::new(users),
+            carts: RefCell::new(HashMap::new()),
+            carts_path: None,
+            reviews: RefCell::default(),
+            clock: Rc::new(SystemClock),
+            idle_timeout: IDLE_TIMEOUT,
+        }
+    }
+
+    /// Whether a session last active at `last_active` was idle for longer than allowed.
+    fn idle(&self, last_active: u64) -> bool {
+        self.clock.now().saturating_sub(last_active) > self.idle_timeout
+    }
+}
+
+/// The selection of a user who logged out, with the keys of its payments.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SavedCart {
+    pub(crate) selected: Vec<Itinerary>,
+    pub(crate) keys: Vec<String>,
+}
+
+/// A payment waiting for an administrator to approve or reject it.
+#[derive(Clone, Debug)]
+pub struct PendingReview {
+    pub id: usize,
+    pub username: String,
+    pub itinerary: Itinerary,
+    pub amount: Money,
+    /// Why the bank risk checks sent the payment to review.
+    pub reason: RiskReason,
+}
+
+/// The payments under review by id, with the purchase each one stopped.
+#[derive(Default)]
+pub(crate) struct Reviews {
+    next: usize,
+    queued: BTreeMap<usize, QueuedReview>,
+}
+
+struct QueuedReview {
+    pending: PendingReview,
+    purchase: Purchase,
+    line: usize,
+    decision: Decision,
+}
+
+enum Decision {
+    Pending(bank_api::Transaction<bank_api::Review>),
+    /// How the bank answered once an administrator approved or rejected the payment.
+    Decided(Result<Authorization, String>),
+}
+
+/// What happened to each selected itinerary during a purchase.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PurchaseOutcome {
+    Paid,
+    /// Funds were held and then released without charging.
+    Voided,
+    /// Paid and then refunded because the purchase as a whole failed.
+    Refunded,
+    /// Paid but the refund failed, the client needs to be refunded manually.
+    RefundFailed(String),
+    Failed(String),
+    SoldOut,
+    NotAttempted,
+}
+
+impl fmt::Display for PurchaseOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PurchaseOutcome::Paid => write!(f, "paid"),
+            PurchaseOutcome::Voided => write!(f, "voided"),
+            PurchaseOutcome::Refunded => write!(f, "refunded"),
+            PurchaseOutcome::RefundFailed(message) => write!(f, "refund failed: {}", message),
+            PurchaseOutcome::Failed(message) => write!(f, "failed: {}", message),
+            PurchaseOutcome::SoldOut => write!(f, "sold out"),
+            PurchaseOutcome::NotAttempted => write!(f, "not attempted"),
+        }
+    }
+}
+
+/// An index of the selection, checked by `cart_index` before a trip is removed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CartIndex(usize);
+
+/// A purchase whose payments are being authorized, kept while the client confirms a challenge.
+pub struct Purchase {
+    pub user: User,
+    pub selected: Vec<Itinerary>,
+    pub keys: Vec<String>,
+    token: String,
+    quote: Quote,
+    outcomes: Vec<PurchaseOutcome>,
+    /// Lines whose funds are held, with their index in the selection.
+    authorizations: Vec<(usize, bank_api::Transaction<bank_api::Authorized>)>,
+}
 
 #[typestate(enumerate = "TSession")]
 pub mod agency_api {
-    use crate::*;
-    use std::result::Result;
+    use super::{Agency, CartIndex, PendingReview, Purchase, PurchaseOutcome};
+    use crate::bank::bank_api;
+    use std::{rc::Rc, result::Result};
+    use travel_agency_common::{
+        catalog::Trip,
+        itinerary::{Itinerary, RouteOrder},
+        money::{Money, MoneyError},
+        pricing::Quote,
+        query::SearchQuery,
+        users::User,
+    };
+
     #[automata]
-    pub struct Session;
+    pub struct Session {
+        pub agency: Rc<Agency>,
+    }
 
     #[state]
     pub struct Guest;
     pub trait Guest {
-        fn init() -> Guest;
+        fn init(agency: Rc<Agency>) -> Guest;
         fn login(self, username: &str, password: &str) -> Login;
+        /// Create an account and log in with it.
+        fn register(self, username: &str, password: &str, email: &str) -> Registration;
+        /// Log in to manage the agency, only users with the admin role may.
+        fn admin_login(self, username: &str, password: &str) -> AdminLogin;
+        fn close(self);
     }
 
     pub enum Login {
         Empty,
+        /// The user had saved a cart.
+        NonEmpty,
+        /// The user logged out while a payment waited for review.
+        AwaitingReview,
         Error,
     }
 
+    pub enum Registration {
+        Empty,
+        Error,
+    }
+
+    pub enum AdminLogin {
+        Admin,
+        Error,
+    }
+
+    /// An administrator managing the catalog, the users and the bank.
+    #[state]
+    pub struct Admin {
+        pub user: User,
+    }
+    pub trait Admin {
+        /// Every trip of the catalog, retired ones included, with its seats sold.
+        fn trips(&self) -> Vec<(Trip, usize)>;
+        fn create_trip(
+            &mut self,
+            from: &str,
+            to: &str,
+            price: Money,
+            capacity: usize,
+        ) -> Result<Trip, String>;
+        /// Change the cities and capacity of a trip.
+        fn edit_trip(
+            &mut self,
+            id: usize,
+            from: &str,
+            to: &str,
+            capacity: usize,
+        ) -> Result<Trip, String>;
+        fn reprice_trip(&mut self, id: usize, price: Money) -> Result<Trip, String>;
+        /// Stop selling a trip, selections holding it can no longer be bought.
+        fn retire_trip(&mut self, id: usize) -> Result<Trip, String>;
+        fn users(&self) -> Vec<User>;
+        /// Every bank account with its balance and held funds.
+        fn balances(&self) -> Vec<(String, Money, Option<Money>)>;
+        /// The payments waiting for an administrator, oldest first.
+        fn reviews(&self) -> Vec<PendingReview>;
+        /// Approve the payment under review `id`, its user carries on with the purchase.
+        fn approve(&mut self, id: usize) -> Result<PendingReview, String>;
+        /// Reject the payment under review `id`, the purchase of its user fails.
+        fn reject(&mut self, id: usize) -> Result<PendingReview, String>;
+        fn logout(self) -> Guest;
+        fn close(self);
+    }
+
     #[state]
     pub struct Error {
         pub message: String,
     }
     pub trait Error {
+        /// Go back to try logging in again.
+        fn retry(self) -> Guest;
         fn close(self);
     }
 
     #[state]
     pub struct Empty {
-        pub last_search: Vec<Trip>,
+        pub user: User,
+        pub last_search: Vec<Itinerary>,
+        /// When the user last did something, in seconds since the Unix epoch.
+        pub last_active: u64,
     }
     pub trait Empty {
-        fn search_trip(&mut self, query: &str) -> Vec<Trip>;
+        /// Record activity, expiring the session if it was idle for longer than the agency allows.
+        ///
+        /// Every operation on a logged in session is preceded by it.
+        fn touch(self) -> Activity;
+        fn search_trip(&mut self, query: &SearchQuery) -> Vec<Trip>;
+        fn search_route(&mut self, from: &str, to: &str, order: RouteOrder) -> Vec<Itinerary>;
         fn add_trip(self, idx: usize) -> Selection;
+        fn cart(&self) -> &[Itinerary];
+        fn logout(self) -> Guest;
         fn close(self);
     }
 
     #[state]
     pub struct NonEmpty {
-        pub last_search: Vec<Trip>,
-        pub selected: Vec<Trip>,
+        pub user: User,
+        pub last_search: Vec<Itinerary>,
+        pub selected: Vec<Itinerary>,
+        /// Idempotency key of each selected itinerary, a retried `buy` never charges it twice.
+        pub keys: Vec<String>,
+        /// When the user last did something, in seconds since the Unix epoch.
+        pub last_active: u64,
     }
     pub trait NonEmpty {
-        fn search_trip(&mut self, query: &str) -> Vec<Trip>;
+        /// Record activity, expiring the session if it was idle for longer than the agency allows.
+        ///
+        /// The selection is kept and restored when the user logs back in.
+        fn touch(self) -> Activity;
+        fn search_trip(&mut self, query: &SearchQuery) -> Vec<Trip>;
+        fn search_route(&mut self, from: &str, to: &str, order: RouteOrder) -> Vec<Itinerary>;
         fn add_trip(&mut self, idx: usize) -> Result<(), String>;
+        fn cart(&self) -> &[Itinerary];
+        /// Check that `idx` is in the selection, before removing it with `remove_trip`.
+        fn cart_index(&self, idx: usize) -> Result<CartIndex, String>;
+        fn remove_trip(self, idx: CartIndex) -> Removal;
+        fn quote(&self) -> Result<Quote, MoneyError>;
+        /// Pay for the selection with a payment token issued by the bank.
+        ///
+        /// An expired session is not charged, whether or not it was touched first.
         fn buy(self, token: &str) -> Transaction;
+        /// Log out, keeping the selection for the next login of the user if `save_cart`.
+        fn logout(self, save_cart: bool) -> Guest;
+        fn close(self);
+    }
+
+    /// The session was idle for too long, the user has to log in again to carry on.
+    #[state]
+    pub struct Expired {
+        pub user: User,
+        /// The selection of the expired session, empty if there was none.
+        pub selected: Vec<Itinerary>,
+        pub keys: Vec<String>,
+    }
+    pub trait Expired {
+        /// Log back in as the same user, restoring the selection.
+        fn login(self, password: &str) -> Login;
+        /// Log out, keeping the selection for the next login of the user.
+        fn logout(self) -> Guest;
+        fn close(self);
+    }
+
+    /// The bank asked the client to confirm a payment with a one-time code.
+    #[state]
+    pub struct AwaitingChallenge {
+        pub purchase: Purchase,
+        /// Line of the selection the challenge authorizes.
+        pub line: usize,
+        pub challenge: bank_api::Transaction<bank_api::Challenge>,
+    }
+    pub trait AwaitingChallenge {
+        /// Confirm the payment with the code sent by the bank and carry on with the purchase.
+        fn confirm(self, code: &str) -> Transaction;
+        /// Give up the purchase and go back to the selection.
+        fn cancel(self) -> NonEmpty;
+        fn close(self);
+    }
+
+    /// The bank sent a payment to manual review, an administrator has to approve or reject it.
+    ///
+    /// The purchase waits in the review queue of the agency, where it is left on logout.
+    #[state]
+    pub struct AwaitingReview {
+        pub user: User,
+        /// The selection being bought.
+        pub selected: Vec<Itinerary>,
+        pub review: PendingReview,
+    }
+    pub trait AwaitingReview {
+        /// Carry on with the purchase if an administrator decided, otherwise keep waiting.
+        fn check(self) -> Transaction;
+        /// Give up the purchase and go back to the selection.
+        fn cancel(self) -> NonEmpty;
+        /// Log out, the payment stays under review until the next login of the user.
+        fn logout(self) -> Guest;
         fn close(self);
     }
 
     #[state]
     pub struct RetryError {
+        pub user: User,
         pub message: String,
-        pub selected: Vec<Trip>,
+        pub selected: Vec<Itinerary>,
+        pub keys: Vec<String>,
+        pub outcomes: Vec<PurchaseOutcome>,
     }
     pub trait RetryError {
         fn retry(self) -> NonEmpty;
+        /// Log out, keeping the selection for the next login of the user if `save_cart`.
+        fn logout(self, save_cart: bool) -> Guest;
+        fn close(self);
+    }
+
+    #[state]
+    pub struct SoldOut {
+        pub user: User,
+        pub trip: Trip,
+        pub selected: Vec<Itinerary>,
+        pub keys: Vec<String>,
+        pub outcomes: Vec<PurchaseOutcome>,
+    }
+    pub trait SoldOut {
+        fn remove_sold_out(self) -> Selection;
         fn close(self);
     }
 
@@ -72,155 +392,1587 @@ pub mod agency_api {
         Empty,
     }
 
+    pub enum Removal {
+        NonEmpty,
+        Empty,
+    }
+
+    pub enum Activity {
+        Empty,
+        NonEmpty,
+        Expired,
+    }
+
     pub enum Transaction {
         Empty,
+        Expired,
+        AwaitingChallenge,
+        AwaitingReview,
         RetryError,
+        SoldOut,
     }
 }
 
 impl GuestState for Session<Guest> {
-    fn init() -> Self {
-        return Session::<Guest> { state: Guest };
+    fn init(agency: Rc<Agency>) -> Self {
+        return Session::<Guest> {
+            agency,
+            state: Guest,
+        };
     }
     fn login(self, username: &str, password: &str) -> Login {
-        if username == "client" && password == "client" {
-            Login::Empty(Session::<Empty> {
+        login(self.agency, username, password)
+    }
+    fn admin_login(self, username: &str, password: &str) -> AdminLogin {
+        match administrator(&self.agency, username, password) {
+            Ok(user) => AdminLogin::Admin(Session::<Admin> {
+                agency: self.agency,
+                state: Admin { user },
+            }),
+            Err(message) => AdminLogin::Error(Session::<Error> {
+                agency: self.agency,
+                state: Error { message },
+            }),
+        }
+    }
+    fn register(self, username: &str, password: &str, email: &str) -> Registration {
+        let registered = self
+            .agency
+            .users
+            .borrow_mut()
+            .register(username, password, email);
+        let last_active = self.agency.clock.now();
+        match registered {
+            Ok(user) => Registration::Empty(Session::<Empty> {
+                agency: self.agency,
                 state: Empty {
+                    user,
                     last_search: vec![],
+                    last_active,
                 },
-            })
-        } else {
-            Login::Error(Session::<Error> {
+            }),
+            Err(err) => Registration::Error(Session::<Error> {
+                agency: self.agency,
                 state: Error {
-                    message: "Invalid credentials".to_string(),
+                    message: err.to_string(),
                 },
-            })
+            }),
         }
     }
+    fn close(self) {
+        // consume
+    }
 }
 
 impl EmptyState for Session<Empty> {
-    fn search_trip(&mut self, query: &str) -> Vec<Trip> {
-        let trips: Vec<Trip> = Trip::mocks()
-            .into_iter()
-            .filter(|trip| trip.matches(query))
-            .collect();
-        self.state.last_search = trips.clone();
+    fn touch(mut self) -> Activity {
+        if self.agency.idle(self.state.last_active) {
+            return Activity::Expired(Session::<Expired> {
+                agency: self.agency,
+                state: Expired {
+                    user: self.state.user,
+                    selected: vec![],
+                    keys: vec![],
+                },
+            });
+        }
+        self.state.last_active = self.agency.clock.now();
+        Activity::Empty(self)
+    }
+    fn search_trip(&mut self, query: &SearchQuery) -> Vec<Trip> {
+        let trips = self.agency.catalog.borrow().search(query);
+        self.state.last_search = trips.iter().cloned().map(Itinerary::from).collect();
         trips
     }
+    fn search_route(&mut self, from: &str, to: &str, order: RouteOrder) -> Vec<Itinerary> {
+        let itineraries = self.agency.catalog.borrow().itineraries(from, to, order);
+        self.state.last_search = itineraries.clone();
+        itineraries
+    }
     fn add_trip(self, idx: usize) -> Selection {
         println!("{:?}", self.state.last_search);
         if idx < self.state.last_search.len() {
             Selection::NonEmpty(Session::<NonEmpty> {
+                agency: self.agency,
                 state: NonEmpty {
+                    user: self.state.user,
                     selected: vec![self.state.last_search[idx].clone()],
+                    keys: vec![payment_key()],
                     last_search: self.state.last_search,
+                    last_active: self.state.last_active,
                 },
             })
         } else {
             Selection::Empty(self)
         }
     }
+    fn cart(&self) -> &[Itinerary] {
+        &[]
+    }
+    fn logout(self) -> Session<Guest> {
+        Session::<Guest> {
+            agency: self.agency,
+            state: Guest,
+        }
+    }
     fn close(self) {
         // consume
     }
 }
 
 impl NonEmptyState for Session<NonEmpty> {
-    fn search_trip(&mut self, query: &str) -> Vec<Trip> {
-        let trips: Vec<Trip> = Trip::mocks()
-            .into_iter()
-            .filter(|trip| trip.matches(query))
-            .collect();
-        self.state.last_search = trips.clone();
+    fn touch(mut self) -> Activity {
+        if self.agency.idle(self.state.last_active) {
+            return Activity::Expired(expire(self));
+        }
+        self.state.last_active = self.agency.clock.now();
+        Activity::NonEmpty(self)
+    }
+    fn search_trip(&mut self, query: &SearchQuery) -> Vec<Trip> {
+        let trips = self.agency.catalog.borrow().search(query);
+        self.state.last_search = trips.iter().cloned().map(Itinerary::from).collect();
         trips
     }
+    fn search_route(&mut self, from: &str, to: &str, order: RouteOrder) -> Vec<Itinerary> {
+        let itineraries = self.agency.catalog.borrow().itineraries(from, to, order);
+        self.state.last_search = itineraries.clone();
+        itineraries
+    }
     fn add_trip(&mut self, idx: usize) -> Result<(), String> {
         if idx < self.state.last_search.len() {
             self.state
                 .selected
                 .push(self.state.last_search[idx].clone());
+            self.state.keys.push(payment_key());
             Ok(())
         } else {
             Err(format!("invalid index: {}", idx))
         }
     }
-    fn buy(self, token: &str) -> Transaction {
-        // TODO finish
-        let mut retain = vec![true; self.state.selected.len()];
-        for (i, trip) in self.state.selected.iter().enumerate() {
-            let transaction =
-                bank_api::Transaction::<bank_api::AccountValidation>::start_transaction(
-                    token,
-                    "travel_agency",
-                    trip.price.try_into().unwrap(),
-                );
-            match transaction.validate_accounts() {
-                bank_api::AccountValidationResult::Valid(validated) => {
-                    match validated.perform_transaction() {
-                        bank_api::TransactionResult::Finish(finish) => {
-                            finish.finish();
-                            retain[i] = false;
-                        }
-                        bank_api::TransactionResult::Error(error) => {
-                            let mut selected = self.state.selected;
-                            let mut j = 0;
-                            selected.retain(|_| (retain[j], j += 1).0);
-                            return Transaction::RetryError(Session::<RetryError> {
-                                state: RetryError {
-                                    message: error.state.message,
-                                    selected,
-                                },
-                            });
-                        }
-                    }
-                }
-                bank_api::AccountValidationResult::Error(error) => {
-                    let mut selected = self.state.selected;
-                    let mut j = 0;
-                    selected.retain(|_| (retain[j], j += 1).0);
-                    return Transaction::RetryError(Session::<RetryError> {
-                        state: RetryError {
-                            message: error.state.message,
-                            selected,
-                        },
-                    });
-                }
+    fn cart(&self) -> &[Itinerary] {
+        &self.state.selected
+    }
+    fn cart_index(&self, idx: usize) -> Result<CartIndex, String> {
+        if idx < self.state.selected.len() {
+            Ok(CartIndex(idx))
+        } else {
+            Err(format!("invalid index: {}", idx))
+        }
+    }
+    fn remove_trip(mut self, CartIndex(idx): CartIndex) -> Removal {
+        self.state.selected.remove(idx);
+        self.state.keys.remove(idx);
+        if self.state.selected.is_empty() {
+            Removal::Empty(Session::<Empty> {
+                agency: self.agency,
+                state: Empty {
+                    user: self.state.user,
+                    last_search: self.state.last_search,
+                    last_active: self.state.last_active,
+                },
+            })
+        } else {
+            Removal::NonEmpty(self)
+        }
+    }
+    fn quote(&self) -> Result<Quote, MoneyError> {
+        Quote::new(&at_catalog_prices(
+            &self.agency.catalog.borrow(),
+            &self.state.selected,
+        ))
+    }
+    fn buy(mut self, token: &str) -> Transaction {
+        if self.agency.idle(self.state.last_active) {
+            return Transaction::Expired(expire(self));
+        }
+        let selected = at_catalog_prices(&self.agency.catalog.borrow(), &self.state.selected);
+        self.state.selected = selected;
+        let quote = match Quote::new(&self.state.selected) {
+            Ok(quote) => quote,
+            Err(err) => {
+                let message = format!("Could not price the selection: {}", err);
+                let outcomes = vec![PurchaseOutcome::NotAttempted; self.state.selected.len()];
+                return Transaction::RetryError(Session::<RetryError> {
+                    agency: self.agency,
+                    state: RetryError {
+                        user: self.state.user,
+                        message,
+                        selected: self.state.selected,
+                        keys: self.state.keys,
+                        outcomes,
+                    },
+                });
             }
+        };
+        let purchase = Purchase {
+            user: self.state.user,
+            outcomes: vec![PurchaseOutcome::NotAttempted; quote.lines.len()],
+            selected: self.state.selected,
+            keys: self.state.keys,
+            token: token.to_string(),
+            quote,
+            authorizations: vec![],
+        };
+        proceed(self.agency, purchase, 0)
+    }
+    fn logout(self, save_cart: bool) -> Session<Guest> {
+        if save_cart {
+            save(
+                &self.agency,
+                self.state.user,
+                self.state.selected,
+                self.state.keys,
+            );
         }
-        Transaction::Empty(Session::<Empty> {
-            state: Empty {
-                last_search: vec![],
-            },
+        Session::<Guest> {
+            agency: self.agency,
+            state: Guest,
+        }
+    }
+    fn close(self) {
+        // consume
+    }
+}
+
+impl AdminState for Session<Admin> {
+    fn trips(&self) -> Vec<(Trip, usize)> {
+        let catalog = self.agency.catalog.borrow();
+        catalog
+            .trips()
+            .iter()
+            .map(|trip| (trip.clone(), catalog.sold(trip)))
+            .collect()
+    }
+    fn create_trip(
+        &mut self,
+        from: &str,
+        to: &str,
+        price: Money,
+        capacity: usize,
+    ) -> Result<Trip, String> {
+        let trip = Trip::new(from.to_string(), to.to_string(), price, capacity);
+        self.agency.catalog.borrow_mut().add(trip)
+    }
+    fn edit_trip(
+        &mut self,
+        id: usize,
+        from: &str,
+        to: &str,
+        capacity: usize,
+    ) -> Result<Trip, String> {
+        self.agency
+            .catalog
+            .borrow_mut()
+            .edit(id, from, to, capacity)
+    }
+    fn reprice_trip(&mut self, id: usize, price: Money) -> Result<Trip, String> {
+        self.agency.catalog.borrow_mut().reprice(id, price)
+    }
+    fn retire_trip(&mut self, id: usize) -> Result<Trip, String> {
+        self.agency.catalog.borrow_mut().retire(id)
+    }
+    fn users(&self) -> Vec<User> {
+        self.agency.users.borrow().users()
+    }
+    fn balances(&self) -> Vec<(String, Money, Option<Money>)> {
+        self.agency
+            .bank
+            .borrow()
+            .balances()
+            .into_iter()
+            .map(|(account, balance, held)| (account.to_string(), balance, held))
+            .collect()
+    }
+    fn reviews(&self) -> Vec<PendingReview> {
+        self.agency
+            .reviews
+            .borrow()
+            .queued
+            .values()
+            .filter(|queued| matches!(queued.decision, Decision::Pending(_)))
+            .map(|queued| queued.pending.clone())
+            .collect()
+    }
+    fn approve(&mut self, id: usize) -> Result<PendingReview, String> {
+        decide(&self.agency, id, |review| hold(review.approve()))
+    }
+    fn reject(&mut self, id: usize) -> Result<PendingReview, String> {
+        decide(&self.agency, id, |review| {
+            Err(finish_error(review.reject()))
         })
     }
+    fn logout(self) -> Session<Guest> {
+        Session::<Guest> {
+            agency: self.agency,
+            state: Guest,
+        }
+    }
     fn close(self) {
         // consume
     }
 }
 
 impl ErrorState for Session<Error> {
+    fn retry(self) -> Session<Guest> {
+        Session::<Guest> {
+            agency: self.agency,
+            state: Guest,
+        }
+    }
+    fn close(self) {
+        // consume
+    }
+}
+
+impl ExpiredState for Session<Expired> {
+    fn login(self, password: &str) -> Login {
+        let username = self.state.user.username.clone();
+        // kept aside so that a failed login does not lose it
+        if !self.state.selected.is_empty() {
+            save(
+                &self.agency,
+                self.state.user,
+                self.state.selected,
+                self.state.keys,
+            );
+        }
+        login(self.agency, &username, password)
+    }
+    fn logout(self) -> Session<Guest> {
+        if !self.state.selected.is_empty() {
+            save(
+                &self.agency,
+                self.state.user,
+                self.state.selected,
+                self.state.keys,
+            );
+        }
+        Session::<Guest> {
+            agency: self.agency,
+            state: Guest,
+        }
+    }
     fn close(self) {
         // consume
     }
 }
 
+impl AwaitingChallengeState for Session<AwaitingChallenge> {
+    fn confirm(self, code: &str) -> Transaction {
+        let AwaitingChallenge {
+            mut purchase,
+            line,
+            challenge,
+        } = self.state;
+        match challenge.confirm(code) {
+            bank_api::ChallengeResult::Authorized(authorized) => {
+                purchase.authorizations.push((line, authorized));
+                proceed(self.agency, purchase, line + 1)
+            }
+            bank_api::ChallengeResult::Challenge(challenge) => {
+                Transaction::AwaitingChallenge(Session::<AwaitingChallenge> {
+                    agency: self.agency,
+                    state: AwaitingChallenge {
+                        purchase,
+                        line,
+                        challenge,
+                    },
+                })
+            }
+            bank_api::ChallengeResult::Error(error) => {
+                let message = finish_error(error);
+                fail(self.agency, purchase, line, message)
+            }
+        }
+    }
+    fn cancel(self) -> Session<NonEmpty> {
+        self.state.challenge.cancel();
+        give_up(self.agency, self.state.purchase)
+    }
+    fn close(self) {
+        self.state.challenge.cancel();
+        for (_, authorization) in self.state.purchase.authorizations {
+            authorization.void();
+        }
+    }
+}
+
+impl AwaitingReviewState for Session<AwaitingReview> {
+    fn check(self) -> Transaction {
+        let (purchase, line, authorization) = match decided(&self.agency, self.state.review.id) {
+            Some(decided) => decided,
+            None => return Transaction::AwaitingReview(self),
+        };
+        match answered(self.agency.clone(), purchase, line, authorization) {
+            ControlFlow::Continue(purchase) => proceed(self.agency, purchase, line + 1),
+            ControlFlow::Break(transaction) => transaction,
+        }
+    }
+    fn cancel(self) -> Session<NonEmpty> {
+        let purchase = withdraw(&self.agency, self.state.review.id);
+        give_up(self.agency, purchase)
+    }
+    fn logout(self) -> Session<Guest> {
+        Session::<Guest> {
+            agency: self.agency,
+            state: Guest,
+        }
+    }
+    fn close(self) {
+        let purchase = withdraw(&self.agency, self.state.review.id);
+        for (_, authorization) in purchase.authorizations {
+            authorization.void();
+        }
+    }
+}
+
+/// Queue the payment of `line` for an administrator, the purchase waits with it.
+fn queue_review(
+    agency: Rc<Agency>,
+    purchase: Purchase,
+    line: usize,
+    review: bank_api::Transaction<bank_api::Review>,
+) -> Session<AwaitingReview> {
+    let user = purchase.user.clone();
+    let selected = purchase.selected.clone();
+    let pending = {
+        let mut reviews = agency.reviews.borrow_mut();
+        reviews.next += 1;
+        let pending = PendingReview {
+            id: reviews.next,
+            username: user.username.clone(),
+            itinerary: purchase.selected[line].clone(),
+            amount: purchase.quote.lines[line].total,
+            reason: review.state.reason.clone(),
+        };
+        let queued = QueuedReview {
+            pending: pending.clone(),
+            purchase,
+            line,
+            decision: Decision::Pending(review),
+        };
+        reviews.queued.insert(pending.id, queued);
+        pending
+    };
+    Session::<AwaitingReview> {
+        agency,
+        state: AwaitingReview {
+            user,
+            selected,
+            review: pending,
+        },
+    }
+}
+
+/// Approve or reject the payment under review `id` with `decide`, once.
+fn decide(
+    agency: &Agency,
+    id: usize,
+    decide: impl FnOnce(bank_api::Transaction<bank_api::Review>) -> Result<Authorization, String>,
+) -> Result<PendingReview, String> {
+    let mut reviews = agency.reviews.borrow_mut();
+    let mut queued = reviews
+        .queued
+        .remove(&id)
+        .ok_or_else(|| format!("no payment under review with id {}", id))?;
+    let decided = match queued.decision {
+        Decision::Pending(review) => {
+            queued.decision = Decision::Decided(decide(review));
+            Ok(queued.pending.clone())
+        }
+        decision => {
+            queued.decision = decision;
+            Err(format!("payment {} was already reviewed", id))
+        }
+    };
+    reviews.queued.insert(id, queued);
+    decided
+}
+
+/// Take the purchase under review `id` out of the queue once an administrator decided,
+/// with the line under review and how the bank answered.
+fn decided(agency: &Agency, id: usize) -> Option<(Purchase, usize, Result<Authorization, String>)> {
+    let mut reviews = agency.reviews.borrow_mut();
+    let queued = reviews
+        .queued
+        .remove(&id)
+        .expect("a session awaiting review has its purchase queued");
+    match queued.decision {
+        Decision::Decided(authorization) => Some((queued.purchase, queued.line, authorization)),
+        Decision::Pending(review) => {
+            let queued = QueuedReview {
+                decision: Decision::Pending(review),
+                ..queued
+            };
+            reviews.queued.insert(id, queued);
+            None
+        }
+    }
+}
+
+/// Take the purchase under review `id` out of the queue, releasing what the review held.
+fn withdraw(agency: &Agency, id: usize) -> Purchase {
+    let queued = agency
+        .reviews
+        .borrow_mut()
+        .queued
+        .remove(&id)
+        .expect("a session awaiting review has its purchase queued");
+    match queued.decision {
+        Decision::Pending(review) => review.cancel(),
+        Decision::Decided(Ok(Authorization::Authorized(authorization))) => authorization.void(),
+        Decision::Decided(Ok(Authorization::Challenge(challenge))) => challenge.cancel(),
+        Decision::Decided(_) => {}
+    }
+    queued.purchase
+}
+
+/// Release the funds held for a purchase given up by the client and go back to its selection.
+fn give_up(agency: Rc<Agency>, purchase: Purchase) -> Session<NonEmpty> {
+    for (_, authorization) in purchase.authorizations {
+        authorization.void();
+    }
+    let last_active = agency.clock.now();
+    Session::<NonEmpty> {
+        agency,
+        state: NonEmpty {
+            user: purchase.user,
+            last_search: vec![],
+            selected: purchase.selected,
+            keys: purchase.keys,
+            last_active,
+        },
+    }
+}
+
 impl RetryErrorState for Session<RetryError> {
     fn retry(self) -> Session<NonEmpty> {
+        let last_active = self.agency.clock.now();
         Session::<NonEmpty> {
+            agency: self.agency,
             state: NonEmpty {
+                user: self.state.user,
                 last_search: vec![],
                 selected: self.state.selected,
+                keys: self.state.keys,
+                last_active,
             },
         }
     }
+    fn logout(self, save_cart: bool) -> Session<Guest> {
+        if save_cart {
+            save(
+                &self.agency,
+                self.state.user,
+                self.state.selected,
+                self.state.keys,
+            );
+        }
+        Session::<Guest> {
+            agency: self.agency,
+            state: Guest,
+        }
+    }
     fn close(self) {
         // consume
     }
 }
 
+impl SoldOutState for Session<SoldOut> {
+    fn remove_sold_out(self) -> Selection {
+        let sold_out = self.state.trip.id;
+        let (selected, keys): (Vec<_>, Vec<_>) = self
+            .state
+            .selected
+            .into_iter()
+            .zip(self.state.keys)
+            .filter(|(itinerary, _)| itinerary.legs.iter().all(|leg| leg.id != sold_out))
+            .unzip();
+        let last_active = self.agency.clock.now();
+        if selected.is_empty() {
+            Selection::Empty(Session::<Empty> {
+                agency: self.agency,
+                state: Empty {
+                    user: self.state.user,
+                    last_search: vec![],
+                    last_active,
+                },
+            })
+        } else {
+            Selection::NonEmpty(Session::<NonEmpty> {
+                agency: self.agency,
+                state: NonEmpty {
+                    user: self.state.user,
+                    last_search: vec![],
+                    selected,
+                    keys,
+                    last_active,
+                },
+            })
+        }
+    }
+    fn close(self) {
+        // consume
+    }
+}
+
+/// End a session that was idle for too long, keeping its selection.
+fn expire(session: Session<NonEmpty>) -> Session<Expired> {
+    Session::<Expired> {
+        agency: session.agency,
+        state: Expired {
+            user: session.state.user,
+            selected: session.state.selected,
+            keys: session.state.keys,
+        },
+    }
+}
+
+/// Log `username` in, back to the payment they left under review or restoring the cart
+/// they saved if any.
+fn login(agency: Rc<Agency>, username: &str, password: &str) -> Login {
+    let authenticated = agency
+        .users
+        .borrow_mut()
+        .authenticate(username, password, &*agency.clock);
+    let user = match authenticated {
+        Ok(user) => user,
+        Err(err) => {
+            return Login::Error(Session::<Error> {
+                agency,
+                state: Error {
+                    message: err.to_string(),
+                },
+            })
+        }
+    };
+    let waiting = agency
+        .reviews
+        .borrow()
+        .queued
+        .values()
+        .find(|queued| queued.pending.username == user.username)
+        .map(|queued| (queued.purchase.selected.clone(), queued.pending.clone()));
+    if let Some((selected, review)) = waiting {
+        return Login::AwaitingReview(Session::<AwaitingReview> {
+            agency,
+            state: AwaitingReview {
+                user,
+                selected,
+                review,
+            },
+        });
+    }
+    let saved = agency.carts.borrow_mut().remove(&user.username);
+    if saved.is_some() {
+        save_carts(&agency);
+    }
+    let last_active = agency.clock.now();
+    match saved {
+        Some(cart) => Login::NonEmpty(Session::<NonEmpty> {
+            agency,
+            state: NonEmpty {
+                user,
+                last_search: vec![],
+                selected: cart.selected,
+                keys: cart.keys,
+                last_active,
+            },
+        }),
+        None => Login::Empty(Session::<Empty> {
+            agency,
+            state: Empty {
+                user,
+                last_search: vec![],
+                last_active,
+            },
+        }),
+    }
+}
+
+/// Authenticate `username`, who has to be an administrator.
+fn administrator(agency: &Agency, username: &str, password: &str) -> Result<User, String> {
+    let authenticated = agency
+        .users
+        .borrow_mut()
+        .authenticate(username, password, &*agency.clock);
+    match authenticated {
+        Ok(user) if user.role == Role::Admin => Ok(user),
+        Ok(user) => Err(format!("{} is not an administrator", user.username)),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// The `selected` itineraries at the prices of the catalog, which purchases are charged.
+///
+/// Legs no longer in the catalog keep their price, reserving their seats fails.
+fn at_catalog_prices(catalog: &Catalog, selected: &[Itinerary]) -> Vec<Itinerary> {
+    let mut selected = selected.to_vec();
+    for leg in selected
+        .iter_mut()
+        .flat_map(|itinerary| &mut itinerary.legs)
+    {
+        if let Some(known) = catalog.current(leg) {
+            leg.price = known.price;
+        }
+    }
+    selected
+}
+
+/// Keep the selection of `user` for their next login.
+fn save(agency: &Agency, user: User, selected: Vec<Itinerary>, keys: Vec<String>) {
+    agency
+        .carts
+        .borrow_mut()
+        .insert(user.username, SavedCart { selected, keys });
+    save_carts(agency);
+}
+
+fn save_carts(agency: &Agency) {
+    if let Err(err) = agency.save_carts() {
+        // the carts are still restored until the process ends
+        eprintln!("could not save the carts: {}", err);
+    }
+}
+
+/// Hold the funds of the lines from `start` on, then reserve the seats and capture the payments.
+///
+/// Stops at the first line the bank wants the client to confirm.
+fn proceed(agency: Rc<Agency>, mut purchase: Purchase, start: usize) -> Transaction {
+    let bank = &agency.bank;
+    // funds for the whole selection are held before confirming any seat
+    for i in start..purchase.quote.lines.len() {
+        let total = purchase.quote.lines[i].total;
+        let authorization = authorize(
+            bank,
+            &purchase.keys[i],
+            &purchase.token,
+            AGENCY_ACCOUNT,
+            total,
+        );
+        purchase = match answered(agency.clone(), purchase, i, authorization) {
+            ControlFlow::Continue(purchase) => purchase,
+            ControlFlow::Break(transaction) => return transaction,
+        };
+    }
+    let Purchase {
+        user,
+        selected,
+        mut keys,
+        token,
+        quote,
+        mut outcomes,
+        authorizations,
+    } = purchase;
+    let legs: Vec<Trip> = selected
+        .iter()
+        .flat_map(|itinerary| itinerary.legs.iter().cloned())
+        .collect();
+    let reserved = agency.catalog.borrow_mut().reserve(&legs);
+    if let Err(err) = reserved {
+        void(authorizations, &mut outcomes);
+        compensate(bank, &token, &quote, &mut keys, &mut outcomes);
+        let trip = match err {
+            // a trip whose route changed is as unavailable as a sold out one
+            ReserveError::SoldOut(trip) | ReserveError::Changed(trip) => trip,
+            ReserveError::Save(message) => {
+                return Transaction::RetryError(Session::<RetryError> {
+                    agency,
+                    state: RetryError {
+                        user,
+                        message,
+                        selected,
+                        keys,
+                        outcomes,
+                    },
+                })
+            }
+        };
+        for (outcome, itinerary) in outcomes.iter_mut().zip(&selected) {
+            if *outcome == PurchaseOutcome::Voided
+                && itinerary.legs.iter().any(|leg| leg.id == trip.id)
+            {
+                *outcome = PurchaseOutcome::SoldOut;
+            }
+        }
+        return Transaction::SoldOut(Session::<SoldOut> {
+            agency,
+            state: SoldOut {
+                user,
+                trip,
+                selected,
+                keys,
+                outcomes,
+            },
+        });
+    }
+    let mut authorizations = authorizations.into_iter();
+    while let Some((i, authorization)) = authorizations.next() {
+        let amount = authorization.state.amount;
+        let error = match authorization.capture(amount) {
+            bank_api::TransactionResult::Finish(finish) => {
+                finish.finish();
+                outcomes[i] = PurchaseOutcome::Paid;
+                continue;
+            }
+            bank_api::TransactionResult::Error(error) => error,
+        };
+        let message = finish_error(error);
+        // compensate every itinerary paid so far and release the rest
+        void(authorizations, &mut outcomes);
+        compensate(bank, &token, &quote, &mut keys, &mut outcomes);
+        outcomes[i] = PurchaseOutcome::Failed(message.clone());
+        // if the release cannot be saved the seats stay sold, which never oversells
+        let _ = agency.catalog.borrow_mut().release(&legs);
+        return Transaction::RetryError(Session::<RetryError> {
+            agency,
+            state: RetryError {
+                user,
+                message,
+                selected,
+                keys,
+                outcomes,
+            },
+        });
+    }
+    let last_active = agency.clock.now();
+    Transaction::Empty(Session::<Empty> {
+        agency,
+        state: Empty {
+            user,
+            last_search: vec![],
+            last_active,
+        },
+    })
+}
+
+/// Record how the bank answered the authorization of `line`.
+///
+/// Continues with the purchase when it can go on with the next line, otherwise breaks
+/// with the state it stops in.
+fn answered(
+    agency: Rc<Agency>,
+    mut purchase: Purchase,
+    line: usize,
+    authorization: Result<Authorization, String>,
+) -> ControlFlow<Transaction, Purchase> {
+    match authorization {
+        Ok(Authorization::Authorized(authorization)) => {
+            purchase.authorizations.push((line, authorization));
+            ControlFlow::Continue(purchase)
+        }
+        Ok(Authorization::Challenge(challenge)) => ControlFlow::Break(
+            Transaction::AwaitingChallenge(Session::<AwaitingChallenge> {
+                agency,
+                state: AwaitingChallenge {
+                    purchase,
+                    line,
+                    challenge,
+                },
+            }),
+        ),
+        Ok(Authorization::Review(review)) => ControlFlow::Break(Transaction::AwaitingReview(
+            queue_review(agency, purchase, line, review),
+        )),
+        // charged by an earlier attempt whose refund failed
+        Ok(Authorization::Settled) => {
+            purchase.outcomes[line] = PurchaseOutcome::Paid;
+            ControlFlow::Continue(purchase)
+        }
+        Err(message) => ControlFlow::Break(fail(agency, purchase, line, message)),
+    }
+}
+
+/// Give up a purchase whose `line` could not be authorized, releasing and refunding the others.
+fn fail(agency: Rc<Agency>, mut purchase: Purchase, line: usize, message: String) -> Transaction {
+    void(purchase.authorizations, &mut purchase.outcomes);
+    purchase.outcomes[line] = PurchaseOutcome::Failed(message.clone());
+    compensate(
+        &agency.bank,
+        &purchase.token,
+        &purchase.quote,
+        &mut purchase.keys,
+        &mut purchase.outcomes,
+    );
+    Transaction::RetryError(Session::<RetryError> {
+        agency,
+        state: RetryError {
+            user: purchase.user,
+            message,
+            selected: purchase.selected,
+            keys: purchase.keys,
+            outcomes: purchase.outcomes,
+        },
+    })
+}
+
+/// A key no other cart line shares, so that a line is charged at most once
+/// however many times its purchase is retried.
+fn payment_key() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos());
+    format!(
+        "{}-{}-{}",
+        process::id(),
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Release the funds held for the lines that were not captured.
+fn void(
+    authorizations: impl IntoIterator<Item = (usize, bank_api::Transaction<bank_api::Authorized>)>,
+    outcomes: &mut [PurchaseOutcome],
+) {
+    for (i, authorization) in authorizations {
+        authorization.void();
+        outcomes[i] = PurchaseOutcome::Voided;
+    }
+}
+
+/// Refund every paid line of a failed purchase.
+///
+/// Refunded lines get a new key so that the next attempt charges them again,
+/// lines whose refund failed keep theirs and are not charged twice.
+fn compensate(
+    bank: &Rc<RefCell<Bank>>,
+    token: &str,
+    quote: &Quote,
+    keys: &mut [String],
+    outcomes: &mut [PurchaseOutcome],
+) {
+    if !outcomes.contains(&PurchaseOutcome::Paid) {
+        return;
+    }
+    // refunds are paid from the agency account, with a token of its own
+    let refund_token = bank
+        .borrow_mut()
+        .issue_token(AGENCY_ACCOUNT, REFUND_TOKEN_TTL)
+        .map_err(|err| format!("Could not issue a refund token: {}", err));
+    let lines = outcomes.iter_mut().zip(&quote.lines).zip(keys);
+    for ((outcome, line), key) in lines {
+        if *outcome != PurchaseOutcome::Paid {
+            continue;
+        }
+        let refunded = refund_token
+            .as_ref()
+            .map_err(String::clone)
+            .and_then(|refund_token| transfer(bank, refund_token, token, line.total));
+        *outcome = match refunded {
+            Ok(()) => {
+                *key = payment_key();
+                PurchaseOutcome::Refunded
+            }
+            Err(message) => PurchaseOutcome::RefundFailed(message),
+        };
+    }
+}
+
+/// Run a bank transaction from start to finish, paid with the `from` token.
+///
+/// Only used for refunds, which the agency approves if they are sent to review.
+fn transfer(bank: &Rc<RefCell<Bank>>, from: &str, to: &str, amount: Money) -> Result<(), String> {
+    let transaction = bank_api::Transaction::<bank_api::AccountValidation>::start_transaction(
+        bank.clone(),
+        None,
+        from,
+        to,
+        amount,
+    );
+    let error = match transaction.validate_accounts() {
+        bank_api::AccountValidationResult::RiskCheck(checked) => {
+            let validated = match checked.check_risk() {
+                bank_api::RiskResult::Valid(validated) => validated,
+                bank_api::RiskResult::Review(review) => review.approve(),
+                bank_api::RiskResult::Error(error) => return Err(finish_error(error)),
+            };
+            match validated.perform_transaction() {
+                bank_api::TransactionResult::Finish(finish) => {
+                    finish.finish();
+                    return Ok(());
+                }
+                bank_api::TransactionResult::Error(error) => error,
+            }
+        }
+        bank_api::AccountValidationResult::Error(error) => error,
+        bank_api::AccountValidationResult::Finish(finish) => {
+            finish.finish();
+            return Ok(());
+        }
+    };
+    Err(finish_error(error))
+}
+
+/// How the bank answered an authorization.
+enum Authorization {
+    Authorized(bank_api::Transaction<bank_api::Authorized>),
+    /// The client has to confirm the payment first.
+    Challenge(bank_api::Transaction<bank_api::Challenge>),
+    /// An administrator has to approve the payment first.
+    Review(bank_api::Transaction<bank_api::Review>),
+    /// The payment with this key already went through.
+    Settled,
+}
+
+/// Hold `amount` on the account of the `from` token, to be captured or voided later.
+fn authorize(
+    bank: &Rc<RefCell<Bank>>,
+    key: &str,
+    from: &str,
+    to: &str,
+    amount: Money,
+) -> Result<Authorization, String> {
+    let transaction = bank_api::Transaction::<bank_api::AccountValidation>::start_transaction(
+        bank.clone(),
+        Some(key),
+        from,
+        to,
+        amount,
+    );
+    let error = match transaction.validate_accounts() {
+        bank_api::AccountValidationResult::RiskCheck(checked) => match checked.check_risk() {
+            bank_api::RiskResult::Valid(validated) => return hold(validated),
+            bank_api::RiskResult::Review(review) => return Ok(Authorization::Review(review)),
+            bank_api::RiskResult::Error(error) => error,
+        },
+        bank_api::AccountValidationResult::Error(error) => error,
+        bank_api::AccountValidationResult::Finish(finish) => {
+            finish.finish();
+            return Ok(Authorization::Settled);
+        }
+    };
+    Err(finish_error(error))
+}
+
+/// Hold the funds of a transaction that passed the risk checks.
+fn hold(validated: bank_api::Transaction<bank_api::Valid>) -> Result<Authorization, String> {
+    match validated.authorize() {
+        bank_api::AuthorizationResult::Authorized(authorized) => {
+            Ok(Authorization::Authorized(authorized))
+        }
+        bank_api::AuthorizationResult::Challenge(challenge) => {
+            Ok(Authorization::Challenge(challenge))
+        }
+        bank_api::AuthorizationResult::Error(error) => Err(finish_error(error)),
+    }
+}
+
+fn finish_error(error: bank_api::Transaction<bank_api::Error>) -> String {
+    let message = match &error.state.risk {
+        Some(reason) => format!("Declined by the bank risk checks: {}", reason),
+        None => error.state.message.clone(),
+    };
+    error.finish();
+    message
+}
+
 impl TSession {
-    pub fn new() -> Self {
-        Self::Guest(Session::<Guest>::init())
+    pub fn new(agency: Rc<Agency>) -> Self {
+        Self::Guest(Session::<Guest>::init(agency))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bank::RateTable;
+    use std::cell::Cell;
+    use travel_agency_common::money::Currency;
+
+    const USERNAME: &str = "traveller";
+    const PASSWORD: &str = "passw0rd";
+    const ADMIN: &str = "boss";
+    const CLIENT: &str = "valid_client";
+
+    /// A clock that only moves when told to.
+    #[derive(Default)]
+    struct FakeClock(Cell<u64>);
+
+    impl FakeClock {
+        fn advance(&self, seconds: u64) {
+            self.0.set(self.0.get() + seconds);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    /// An agency with one trip, one user and an administrator, in memory, whose time is `clock`.
+    ///
+    /// Its bank has the demo accounts.
+    fn agency(clock: &Rc<FakeClock>) -> Rc<Agency> {
+        Rc::new(new_agency(clock))
+    }
+
+    fn new_agency(clock: &Rc<FakeClock>) -> Agency {
+        let trip = Trip::new(
+            "Lisbon".to_string(),
+            "London".to_string(),
+            Money::from_major(200, Currency::EUR).unwrap(),
+            10,
+        );
+        let mut users = UserStore::default();
+        users
+            .register(USERNAME, PASSWORD, "traveller@example.com")
+            .unwrap();
+        users.add_admin(ADMIN, PASSWORD).unwrap();
+        let mut agency = Agency::new(Catalog::new(vec![trip]), Bank::demo(), users);
+        agency.clock = clock.clone();
+        agency
+    }
+
+    fn logged_in(agency: Rc<Agency>) -> Session<Empty> {
+        match Session::<Guest>::init(agency).login(USERNAME, PASSWORD) {
+            Login::Empty(empty) => empty,
+            Login::NonEmpty(_) => panic!("logged in with a saved cart"),
+            Login::AwaitingReview(_) => panic!("logged in with a payment under review"),
+            Login::Error(error) => panic!("{}", error.state.message),
+        }
+    }
+
+    fn admin(agency: Rc<Agency>) -> Session<Admin> {
+        match Session::<Guest>::init(agency).admin_login(ADMIN, PASSWORD) {
+            AdminLogin::Admin(admin) => admin,
+            AdminLogin::Error(error) => panic!("{}", error.state.message),
+        }
+    }
+
+    fn token(agency: &Agency) -> String {
+        agency.bank.borrow_mut().issue_token(CLIENT, 60).unwrap()
+    }
+
+    /// A purchase of the trip at a price the bank sends to review.
+    fn under_review(agency: Rc<Agency>) -> Session<AwaitingReview> {
+        let price = Money::from_major(2500, Currency::EUR).unwrap();
+        agency.catalog.borrow_mut().reprice(0, price).unwrap();
+        let token = token(&agency);
+        match selected(agency).buy(&token) {
+            Transaction::AwaitingReview(review) => review,
+            _ => panic!("the payment was not sent to review"),
+        }
+    }
+
+    fn eur(major: i64) -> Money {
+        Money::from_major(major, Currency::EUR).unwrap()
+    }
+
+    /// A selection of the trip and a cheaper one, Lisbon -> London then London -> Berlin.
+    fn two_lines(agency: Rc<Agency>) -> Session<NonEmpty> {
+        let trip = Trip::new("London".to_string(), "Berlin".to_string(), eur(100), 10);
+        agency.catalog.borrow_mut().add(trip).unwrap();
+        let mut non_empty = selected(agency);
+        non_empty.search_trip(&"Berlin".parse().unwrap());
+        non_empty.add_trip(0).unwrap();
+        non_empty
+    }
+
+    /// What each line of the selection costs.
+    fn totals(non_empty: &Session<NonEmpty>) -> Vec<Money> {
+        let quote = non_empty.quote().unwrap();
+        quote.lines.iter().map(|line| line.total).collect()
+    }
+
+    /// Charge `line` with its key, as an earlier attempt of the purchase did.
+    fn charged_before(non_empty: &Session<NonEmpty>, line: usize, token: &str) {
+        let total = totals(non_empty)[line];
+        let bank = &non_empty.agency.bank;
+        let key = &non_empty.state.keys[line];
+        match authorize(bank, key, token, AGENCY_ACCOUNT, total) {
+            Ok(Authorization::Authorized(authorization)) => match authorization.capture(total) {
+                bank_api::TransactionResult::Finish(finish) => finish.finish(),
+                bank_api::TransactionResult::Error(error) => panic!("{}", finish_error(error)),
+            },
+            _ => panic!("the line was not authorized"),
+        }
+    }
+
+    fn retry_error(transaction: Transaction) -> Session<RetryError> {
+        match transaction {
+            Transaction::RetryError(error) => error,
+            _ => panic!("the purchase did not fail"),
+        }
+    }
+
+    fn balance(agency: &Agency, account: &str) -> Money {
+        funds(agency, account).0
+    }
+
+    /// The balance of `account` and the funds held on it.
+    fn funds(agency: &Agency, account: &str) -> (Money, Option<Money>) {
+        let bank = agency.bank.borrow();
+        let balances = bank.balances();
+        balances
+            .iter()
+            .find(|(name, _, _)| *name == account)
+            .map(|(_, balance, held)| (*balance, *held))
+            .unwrap()
+    }
+
+    fn unsold(agency: &Agency) -> bool {
+        let catalog = agency.catalog.borrow();
+        catalog.trips().iter().all(|trip| catalog.sold(trip) == 0)
+    }
+
+    fn selected(agency: Rc<Agency>) -> Session<NonEmpty> {
+        let mut empty = logged_in(agency);
+        empty.search_trip(&"Lisbon".parse().unwrap());
+        match empty.add_trip(0) {
+            Selection::NonEmpty(non_empty) => non_empty,
+            Selection::Empty(_) => panic!("the trip was not selected"),
+        }
+    }
+
+    #[test]
+    fn activity_keeps_a_session_alive() {
+        let clock = Rc::new(FakeClock::default());
+        let agency = agency(&clock);
+        let mut empty = logged_in(agency.clone());
+        for _ in 0..3 {
+            clock.advance(agency.idle_timeout);
+            empty = match empty.touch() {
+                Activity::Empty(empty) => empty,
+                _ => panic!("the session expired while in use"),
+            };
+        }
+    }
+
+    #[test]
+    fn idle_sessions_expire() {
+        let clock = Rc::new(FakeClock::default());
+        let agency = agency(&clock);
+        let empty = logged_in(agency.clone());
+        clock.advance(agency.idle_timeout + 1);
+        match empty.touch() {
+            Activity::Expired(expired) => {
+                assert_eq!(expired.state.user.username, USERNAME);
+                assert!(expired.state.selected.is_empty());
+            }
+            _ => panic!("the idle session did not expire"),
+        }
+    }
+
+    #[test]
+    fn expired_sessions_keep_the_cart() {
+        let clock = Rc::new(FakeClock::default());
+        let agency = agency(&clock);
+        let non_empty = selected(agency.clone());
+        let keys = non_empty.state.keys.clone();
+        clock.advance(agency.idle_timeout + 1);
+        let expired = match non_empty.touch() {
+            Activity::Expired(expired) => expired,
+            _ => panic!("the idle session did not expire"),
+        };
+        assert_eq!(expired.state.selected.len(), 1);
+        let expired = match expired.login("wrong password") {
+            Login::Error(error) => {
+                assert_eq!(error.state.message, "Invalid credentials");
+                // the cart waits for the next login of its user
+                match error.retry().login(USERNAME, PASSWORD) {
+                    Login::NonEmpty(non_empty) => non_empty,
+                    _ => panic!("the cart was not restored"),
+                }
+            }
+            _ => panic!("logged in with a wrong password"),
+        };
+        assert_eq!(expired.state.keys, keys);
+        clock.advance(agency.idle_timeout + 1);
+        let expired = match expired.touch() {
+            Activity::Expired(expired) => expired,
+            _ => panic!("the idle session did not expire"),
+        };
+        match expired.login(PASSWORD) {
+            Login::NonEmpty(non_empty) => {
+                assert_eq!(non_empty.state.user.username, USERNAME);
+                assert_eq!(non_empty.state.selected[0].origin(), "Lisbon");
+                assert_eq!(non_empty.state.keys, keys);
+                assert_eq!(non_empty.state.last_active, clock.now());
+            }
+            _ => panic!("the cart was not restored"),
+        }
+    }
+
+    #[test]
+    fn expired_sessions_are_not_charged() {
+        let clock = Rc::new(FakeClock::default());
+        let agency = agency(&clock);
+        let non_empty = selected(agency.clone());
+        let before = funds(&agency, CLIENT);
+        clock.advance(agency.idle_timeout + 1);
+        // bought without touching the session first
+        match non_empty.buy(&token(&agency)) {
+            Transaction::Expired(expired) => assert_eq!(expired.state.selected.len(), 1),
+            _ => panic!("the expired session bought the selection"),
+        }
+        assert_eq!(funds(&agency, CLIENT), before);
+        assert!(unsold(&agency));
+    }
+
+    #[test]
+    fn removing_checks_the_index() {
+        let non_empty = selected(agency(&Rc::new(FakeClock::default())));
+        assert_eq!(non_empty.cart_index(1), Err("invalid index: 1".to_string()));
+        let idx = non_empty.cart_index(0).unwrap();
+        match non_empty.remove_trip(idx) {
+            Removal::Empty(empty) => assert!(empty.cart().is_empty()),
+            Removal::NonEmpty(_) => panic!("the trip was not removed"),
+        }
+    }
+
+    #[test]
+    fn saved_carts_outlive_the_process() {
+        let path = std::env::temp_dir().join(format!("travel-agency-carts-{}.json", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let clock = Rc::new(FakeClock::default());
+        let reopened = || {
+            let mut agency = new_agency(&clock);
+            assert!(agency.open_carts(&path).unwrap().is_empty());
+            Rc::new(agency)
+        };
+        let non_empty = selected(reopened());
+        let keys = non_empty.state.keys.clone();
+        non_empty.logout(true);
+        match Session::<Guest>::init(reopened()).login(USERNAME, PASSWORD) {
+            Login::NonEmpty(non_empty) => {
+                assert_eq!(non_empty.state.selected[0].destination(), "London");
+                assert_eq!(non_empty.state.keys, keys);
+            }
+            _ => panic!("the saved cart was not restored"),
+        }
+        // restoring the cart took it out of the file
+        assert!(matches!(
+            Session::<Guest>::init(reopened()).login(USERNAME, PASSWORD),
+            Login::Empty(_)
+        ));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn approved_reviews_carry_on_at_the_next_login() {
+        let agency = agency(&Rc::new(FakeClock::default()));
+        let review = under_review(agency.clone());
+        let id = review.state.review.id;
+        let paid = review.state.review.amount;
+        let review = match review.check() {
+            Transaction::AwaitingReview(review) => review,
+            _ => panic!("the purchase went on before the review"),
+        };
+        review.logout();
+        let mut admin = admin(agency.clone());
+        let pending = admin.reviews();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].username, USERNAME);
+        assert!(admin.approve(id + 1).is_err());
+        assert_eq!(admin.approve(id).unwrap().id, id);
+        assert!(admin.reject(id).is_err());
+        assert!(admin.reviews().is_empty());
+        admin.logout();
+        let review = match Session::<Guest>::init(agency.clone()).login(USERNAME, PASSWORD) {
+            Login::AwaitingReview(review) => review,
+            _ => panic!("the payment under review was not restored"),
+        };
+        // approved payments still go through the challenge of large amounts
+        let challenge = match review.check() {
+            Transaction::AwaitingChallenge(challenge) => challenge,
+            _ => panic!("the approved payment was not authorized"),
+        };
+        let code = challenge.state.challenge.state.code.clone();
+        assert!(matches!(challenge.confirm(&code), Transaction::Empty(_)));
+        let funds = Money::from_major(5000, Currency::EUR).unwrap();
+        assert_eq!(balance(&agency, CLIENT), funds.checked_sub(paid).unwrap());
+        assert!(agency.reviews.borrow().queued.is_empty());
+    }
+
+    #[test]
+    fn rejected_reviews_fail_the_purchase() {
+        let agency = agency(&Rc::new(FakeClock::default()));
+        let review = under_review(agency.clone());
+        admin(agency.clone())
+            .reject(review.state.review.id)
+            .unwrap();
+        match review.check() {
+            Transaction::RetryError(error) => {
+                assert!(matches!(
+                    error.state.outcomes[..],
+                    [PurchaseOutcome::Failed(_)]
+                ))
+            }
+            _ => panic!("the rejected payment did not fail the purchase"),
+        }
+        assert_eq!(
+            balance(&agency, CLIENT),
+            Money::from_major(5000, Currency::EUR).unwrap()
+        );
+        assert!(agency.reviews.borrow().queued.is_empty());
+    }
+
+    #[test]
+    fn retries_keep_the_keys_of_lines_not_charged() {
+        let agency = agency(&Rc::new(FakeClock::default()));
+        let non_empty = two_lines(agency.clone());
+        let keys = non_empty.state.keys.clone();
+        let [first, second] = totals(&non_empty)[..] else {
+            panic!("the selection has two lines")
+        };
+        // enough for the first line only
+        let funds = first.checked_add(eur(10)).unwrap();
+        agency.bank.borrow_mut().set_balance(CLIENT, funds);
+        let error = retry_error(non_empty.buy(&token(&agency)));
+        assert!(matches!(
+            error.state.outcomes[..],
+            [PurchaseOutcome::Voided, PurchaseOutcome::Failed(_)]
+        ));
+        assert_eq!(error.state.message, "Insufficient funds");
+        assert_eq!(balance(&agency, CLIENT), funds);
+        let non_empty = error.retry();
+        assert_eq!(non_empty.state.keys, keys);
+        let funds = funds.checked_add(second).unwrap();
+        agency.bank.borrow_mut().set_balance(CLIENT, funds);
+        assert!(matches!(
+            non_empty.buy(&token(&agency)),
+            Transaction::Empty(_)
+        ));
+        let spent = first.checked_add(second).unwrap();
+        assert_eq!(balance(&agency, CLIENT), funds.checked_sub(spent).unwrap());
+    }
+
+    #[test]
+    fn refunded_lines_are_charged_again_with_a_new_key() {
+        let agency = agency(&Rc::new(FakeClock::default()));
+        let non_empty = two_lines(agency.clone());
+        let keys = non_empty.state.keys.clone();
+        let [first, second] = totals(&non_empty)[..] else {
+            panic!("the selection has two lines")
+        };
+        let funds = first.checked_add(eur(10)).unwrap();
+        agency.bank.borrow_mut().set_balance(CLIENT, funds);
+        let token = token(&agency);
+        charged_before(&non_empty, 0, &token);
+        // the first line is settled, the second cannot be paid and the first is refunded
+        let error = retry_error(non_empty.buy(&token));
+        assert!(matches!(
+            error.state.outcomes[..],
+            [PurchaseOutcome::Refunded, PurchaseOutcome::Failed(_)]
+        ));
+        assert_eq!(balance(&agency, CLIENT), funds);
+        let non_empty = error.retry();
+        assert_ne!(non_empty.state.keys[0], keys[0]);
+        assert_eq!(non_empty.state.keys[1], keys[1]);
+        let funds = funds.checked_add(second).unwrap();
+        agency.bank.borrow_mut().set_balance(CLIENT, funds);
+        assert!(matches!(non_empty.buy(&token), Transaction::Empty(_)));
+        let spent = first.checked_add(second).unwrap();
+        assert_eq!(balance(&agency, CLIENT), funds.checked_sub(spent).unwrap());
+    }
+
+    #[test]
+    fn lines_whose_refund_failed_are_not_charged_twice() {
+        let agency = agency(&Rc::new(FakeClock::default()));
+        let rates = || RateTable::parse("EUR,GBP,0.85").unwrap();
+        agency.bank.borrow_mut().set_rates(rates());
+        let non_empty = two_lines(agency.clone());
+        let keys = non_empty.state.keys.clone();
+        let token = agency
+            .bank
+            .borrow_mut()
+            .issue_token("gbp_client", 60)
+            .unwrap();
+        charged_before(&non_empty, 0, &token);
+        let funds = balance(&agency, "gbp_client");
+        // without rates the second line cannot be held, nor the first refunded
+        agency.bank.borrow_mut().set_rates(RateTable::default());
+        let error = retry_error(non_empty.buy(&token));
+        assert!(matches!(
+            error.state.outcomes[..],
+            [PurchaseOutcome::RefundFailed(_), PurchaseOutcome::Failed(_)]
+        ));
+        assert_eq!(balance(&agency, "gbp_client"), funds);
+        let non_empty = error.retry();
+        assert_eq!(non_empty.state.keys, keys);
+        agency.bank.borrow_mut().set_rates(rates());
+        let second = totals(&non_empty)[1];
+        assert!(matches!(non_empty.buy(&token), Transaction::Empty(_)));
+        // only the second line is charged, in pounds
+        let second = second
+            .convert(Currency::GBP, "0.85".parse().unwrap())
+            .unwrap();
+        assert_eq!(
+            balance(&agency, "gbp_client"),
+            funds.checked_sub(second).unwrap()
+        );
+    }
+
+    #[test]
+    fn failed_captures_refund_the_lines_paid() {
+        let agency = agency(&Rc::new(FakeClock::default()));
+        let non_empty = two_lines(agency.clone());
+        let first = totals(&non_empty)[0];
+        // the agency account can be credited the first line and no more
+        let full = Money::new(i64::MAX - first.minor(), Currency::EUR);
+        agency.bank.borrow_mut().set_balance(AGENCY_ACCOUNT, full);
+        let before = funds(&agency, CLIENT);
+        let error = retry_error(non_empty.buy(&token(&agency)));
+        assert!(matches!(
+            error.state.outcomes[..],
+            [PurchaseOutcome::Refunded, PurchaseOutcome::Failed(_)]
+        ));
+        assert_eq!(funds(&agency, CLIENT), before);
+        assert_eq!(balance(&agency, AGENCY_ACCOUNT), full);
+        assert!(unsold(&agency));
+    }
+
+    #[test]
+    fn trips_sold_out_after_the_holds_release_them() {
+        let agency = agency(&Rc::new(FakeClock::default()));
+        let non_empty = two_lines(agency.clone());
+        {
+            let mut catalog = agency.catalog.borrow_mut();
+            let berlin = catalog.trips()[1].clone();
+            catalog.reserve(&vec![berlin; 10]).unwrap();
+        }
+        let before = funds(&agency, CLIENT);
+        match non_empty.buy(&token(&agency)) {
+            Transaction::SoldOut(sold_out) => {
+                assert_eq!(sold_out.state.trip.to, "Berlin");
+                assert!(matches!(
+                    sold_out.state.outcomes[..],
+                    [PurchaseOutcome::Voided, PurchaseOutcome::SoldOut]
+                ));
+            }
+            _ => panic!("the trip was not reported sold out"),
+        }
+        assert_eq!(funds(&agency, CLIENT), before);
+        let catalog = agency.catalog.borrow();
+        assert_eq!(catalog.sold(&catalog.trips()[0]), 0);
+    }
+
+    #[test]
+    fn unsaved_seats_release_the_holds() {
+        let path = std::env::temp_dir().join(format!("travel-agency-seats-{}.csv", process::id()));
+        let sold = path.with_extension("sold");
+        let _ = std::fs::remove_dir(&sold);
+        std::fs::write(&path, "from,to,price,capacity\nLisbon,London,200,10\n").unwrap();
+        let agency = agency(&Rc::new(FakeClock::default()));
+        *agency.catalog.borrow_mut() = Catalog::load(&path).unwrap();
+        // the sold seats cannot be written over a directory
+        std::fs::create_dir(&sold).unwrap();
+        let before = funds(&agency, CLIENT);
+        let error = retry_error(selected(agency.clone()).buy(&token(&agency)));
+        assert!(error
+            .state
+            .message
+            .starts_with("could not save the sold seats"));
+        assert!(matches!(
+            error.state.outcomes[..],
+            [PurchaseOutcome::Voided]
+        ));
+        assert_eq!(funds(&agency, CLIENT), before);
+        assert!(unsold(&agency));
+        let _ = std::fs::remove_dir(&sold);
+        let _ = std::fs::remove_file(&path);
     }
 }
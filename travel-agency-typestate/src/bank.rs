@@ -1,36 +1,1218 @@
 use bank_api::*;
-use std::collections::HashMap;
+use rand::{rngs::OsRng, Rng, RngCore};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+use travel_agency_common::{
+    clock::now,
+    config, file,
+    money::{Currency, Money, MoneyError, Rate},
+};
 use typestate::typestate;
 
+pub const BANK_FLAG: &str = "--bank";
+pub const BANK_ENV: &str = "TRAVEL_AGENCY_BANK";
+pub const DEFAULT_BANK: &str = "bank.csv";
+pub const RATES_FLAG: &str = "--rates";
+pub const RATES_ENV: &str = "TRAVEL_AGENCY_RATES";
+pub const DEFAULT_RATES: &str = "rates.csv";
+/// Seconds a payment token stays valid.
+pub const TOKEN_TTL: u64 = 15 * 60;
+/// Expired tokens are remembered for a day, so that they are reported as expired.
+const TOKEN_RETENTION: u64 = 24 * 60 * 60;
+const TOKEN_PREFIX: &str = "tok_";
+/// Journaled in place of accounts that could not be resolved.
+const UNKNOWN_ACCOUNT: &str = "unknown";
+/// Wrong codes a challenge accepts before the transaction fails.
+pub const CHALLENGE_ATTEMPTS: u32 = 3;
+/// Seconds a passed challenge covers further authorizations of the account.
+const STEP_UP_TTL: u64 = 5 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    /// Balance of the account when the journal was started.
+    Open,
+    Debit,
+    Credit,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Finished,
+    Error(String),
+}
+
+/// One line of the journal, every transaction writes a debit and a credit entry.
+#[derive(Clone, Debug)]
+pub struct JournalEntry {
+    pub transaction: u64,
+    /// Seconds since the UNIX epoch.
+    pub timestamp: u64,
+    pub kind: EntryKind,
+    pub account: String,
+    /// In the currency of the account.
+    pub amount: Money,
+    /// Rate the transaction amount was converted at, when it was in another currency.
+    pub rate: Option<Rate>,
+    /// Idempotency key the transaction was started with.
+    pub key: Option<String>,
+    pub outcome: Outcome,
+}
+
+impl JournalEntry {
+    /// Signed effect of the entry on the account balance.
+    fn effect(&self) -> Result<Money, MoneyError> {
+        match (&self.outcome, self.kind) {
+            (Outcome::Error(_), _) => Ok(Money::zero(self.amount.currency())),
+            (_, EntryKind::Open) | (_, EntryKind::Credit) => Ok(self.amount),
+            (_, EntryKind::Debit) => self.amount.checked_neg(),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        let kind = match self.kind {
+            EntryKind::Open => "open",
+            EntryKind::Debit => "debit",
+            EntryKind::Credit => "credit",
+        };
+        let outcome = match &self.outcome {
+            Outcome::Finished => "finished".to_string(),
+            // the message is the last field so it may contain commas
+            Outcome::Error(message) => format!("error:{}", message),
+        };
+        format!(
+            "{},{},{},{},{},{},{},{}",
+            self.transaction,
+            self.timestamp,
+            kind,
+            self.account,
+            self.amount,
+            self.rate.map_or(String::new(), |rate| rate.to_string()),
+            self.key.as_deref().unwrap_or(""),
+            outcome
+        )
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(8, ',');
+        let transaction = fields.next()?.parse().ok()?;
+        let timestamp = fields.next()?.parse().ok()?;
+        let kind = match fields.next()? {
+            "open" => EntryKind::Open,
+            "debit" => EntryKind::Debit,
+            "credit" => EntryKind::Credit,
+            _ => return None,
+        };
+        let account = fields.next()?.to_string();
+        let amount = fields.next()?.parse().ok()?;
+        let rate = match fields.next()? {
+            "" => None,
+            rate => Some(rate.parse().ok()?),
+        };
+        let key = Some(fields.next()?)
+            .filter(|key| !key.is_empty())
+            .map(str::to_string);
+        let outcome = match fields.next()? {
+            "finished" => Outcome::Finished,
+            outcome => Outcome::Error(outcome.strip_prefix("error:")?.to_string()),
+        };
+        Some(Self {
+            transaction,
+            timestamp,
+            kind,
+            account,
+            amount,
+            rate,
+            key,
+            outcome,
+        })
+    }
+}
+
+impl fmt::Display for JournalEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "#{} at {}: {:?} {} {}",
+            self.transaction, self.timestamp, self.kind, self.account, self.amount
+        )?;
+        if let Some(rate) = self.rate {
+            write!(f, " at {}", rate)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, " [{}]", key)?;
+        }
+        match &self.outcome {
+            Outcome::Finished => Ok(()),
+            Outcome::Error(message) => write!(f, " (error: {})", message),
+        }
+    }
+}
+
+/// The account balances and their journal, shared by every transaction.
+///
+/// Ledgers opened from a file are saved after every transaction,
+/// one `account,balance` pair per line. The journal is kept next to it,
+/// with the `journal` extension, and is only ever appended to.
+///
+/// Funds held by authorizations are only kept in memory,
+/// authorizations that did not survive a restart are as good as voided.
+///
+/// Transactions started with an idempotency key move money at most once,
+/// the keys of finished transactions are recovered from the journal.
+///
+/// Payments are made with the tokens of the [`TokenVault`], kept next to the ledger
+/// with the `tokens` extension.
+#[derive(Debug, Default)]
+pub struct Bank {
+    accounts: HashMap<String, Money>,
+    holds: HashMap<String, Money>,
+    journal: Vec<JournalEntry>,
+    /// Idempotency key of every finished transaction, with the transaction it started.
+    settled: HashMap<String, u64>,
+    /// When each account last passed a challenge.
+    stepped_up: HashMap<String, u64>,
+    vault: TokenVault,
+    rates: RateTable,
+    risk: RiskPolicy,
+    last_transaction: u64,
+    path: Option<PathBuf>,
+}
+
+impl Bank {
+    /// Open the ledger given by `--bank <path>`, the `TRAVEL_AGENCY_BANK` variable or [`DEFAULT_BANK`].
+    ///
+    /// Exchange rates are read from `--rates <path>`, the `TRAVEL_AGENCY_RATES` variable or [`DEFAULT_RATES`].
+    pub fn from_env() -> io::Result<Self> {
+        let mut bank = Self::open(config::path_arg(BANK_FLAG, BANK_ENV, DEFAULT_BANK))?;
+        bank.set_rates(RateTable::load(config::path_arg(
+            RATES_FLAG,
+            RATES_ENV,
+            DEFAULT_RATES,
+        ))?);
+        Ok(bank)
+    }
+
+    /// Open the ledger at `path`, a missing file starts the demo accounts.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let accounts = match fs::read_to_string(path) {
+            Ok(contents) => parse_accounts(&contents)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => demo_accounts(),
+            Err(err) => return Err(err),
+        };
+        let mut bank = Self {
+            accounts,
+            holds: HashMap::new(),
+            journal: vec![],
+            settled: HashMap::new(),
+            stepped_up: HashMap::new(),
+            vault: TokenVault::open(path.with_extension("tokens"))?,
+            rates: RateTable::default(),
+            risk: RiskPolicy::default(),
+            last_transaction: 0,
+            path: Some(path.to_path_buf()),
+        };
+        match fs::read_to_string(bank.journal_path().unwrap()) {
+            Ok(contents) => {
+                bank.journal = parse_journal(&contents)?;
+                bank.last_transaction = bank
+                    .journal
+                    .iter()
+                    .map(|entry| entry.transaction)
+                    .max()
+                    .unwrap_or(0);
+                bank.settled = bank
+                    .journal
+                    .iter()
+                    .filter(|entry| entry.outcome == Outcome::Finished)
+                    .filter_map(|entry| Some((entry.key.clone()?, entry.transaction)))
+                    .collect();
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let mut accounts: Vec<_> = bank.accounts.iter().collect();
+                accounts.sort_by_key(|(account, _)| *account);
+                let opening = accounts
+                    .into_iter()
+                    .map(|(account, &balance)| JournalEntry {
+                        transaction: 0,
+                        timestamp: now(),
+                        kind: EntryKind::Open,
+                        account: account.clone(),
+                        amount: balance,
+                        rate: None,
+                        key: None,
+                        outcome: Outcome::Finished,
+                    })
+                    .collect();
+                bank.record(opening)?;
+            }
+            Err(err) => return Err(err),
+        }
+        Ok(bank)
+    }
+
+    /// A ledger in memory with the demo accounts, for the tests of the agency.
+    #[cfg(test)]
+    pub(crate) fn demo() -> Self {
+        Self {
+            accounts: demo_accounts(),
+            ..Self::default()
+        }
+    }
+
+    /// Set the balance of `account`, opening it if needed.
+    #[cfg(test)]
+    pub(crate) fn set_balance(&mut self, account: &str, balance: Money) {
+        self.accounts.insert(account.to_string(), balance);
+    }
+
+    pub fn set_rates(&mut self, rates: RateTable) {
+        self.rates = rates;
+    }
+
+    /// Every entry of the journal, oldest first.
+    pub fn journal(&self) -> &[JournalEntry] {
+        &self.journal
+    }
+
+    pub fn account_journal<'a>(
+        &'a self,
+        account: &'a str,
+    ) -> impl Iterator<Item = &'a JournalEntry> {
+        self.journal
+            .iter()
+            .filter(move |entry| entry.account == account)
+    }
+
+    /// Check that every finished transaction debits as much as it credits
+    /// and that replaying the journal gives the current balances.
+    ///
+    /// Returns a description of every discrepancy found.
+    pub fn verify(&self) -> Result<(), Vec<String>> {
+        let mut errors = vec![];
+        let mut transactions: BTreeMap<u64, Vec<&JournalEntry>> = BTreeMap::new();
+        let mut balances: BTreeMap<&str, Money> = BTreeMap::new();
+        for entry in &self.journal {
+            if entry.outcome != Outcome::Finished {
+                continue;
+            }
+            let added = entry
+                .effect()
+                .and_then(|effect| accumulate(balances.entry(&entry.account), effect));
+            if let Err(err) = added {
+                errors.push(format!("transaction #{}: {}", entry.transaction, err));
+            }
+            if entry.kind != EntryKind::Open {
+                transactions
+                    .entry(entry.transaction)
+                    .or_default()
+                    .push(entry);
+            }
+        }
+        for (transaction, entries) in transactions {
+            if let Err(message) = balanced(&entries) {
+                errors.push(format!("transaction #{} {}", transaction, message));
+            }
+        }
+        for (account, &balance) in &self.accounts {
+            let replayed = balances
+                .remove(account.as_str())
+                .unwrap_or(Money::zero(balance.currency()));
+            if replayed != balance {
+                errors.push(format!(
+                    "account {} has {} but the journal adds up to {}",
+                    account, balance, replayed
+                ));
+            }
+        }
+        for (account, replayed) in balances {
+            if replayed.minor() != 0 {
+                errors.push(format!(
+                    "unknown account {} adds up to {} in the journal",
+                    account, replayed
+                ));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Transaction that already finished with the idempotency `key`.
+    fn settled(&self, key: &str) -> Option<u64> {
+        self.settled.get(key).copied()
+    }
+
+    fn next_transaction(&mut self) -> u64 {
+        self.last_transaction += 1;
+        self.last_transaction
+    }
+
+    fn journal_path(&self) -> Option<PathBuf> {
+        self.path
+            .as_ref()
+            .map(|path| path.with_extension("journal"))
+    }
+
+    fn record(&mut self, entries: Vec<JournalEntry>) -> io::Result<()> {
+        // settle the keys first, the money moved even if the journal cannot be written
+        for entry in &entries {
+            if let (Some(key), Outcome::Finished) = (&entry.key, &entry.outcome) {
+                self.settled.insert(key.clone(), entry.transaction);
+            }
+        }
+        if let Some(path) = self.journal_path() {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            let mut lines = String::new();
+            for entry in &entries {
+                lines.push_str(&entry.to_line());
+                lines.push('\n');
+            }
+            file.write_all(lines.as_bytes())?;
+        }
+        self.journal.extend(entries);
+        Ok(())
+    }
+
+    /// Journal the debit and credit of a transaction.
+    fn record_transaction(
+        &mut self,
+        transaction: u64,
+        key: Option<&str>,
+        from: &str,
+        to: &str,
+        postings: Postings,
+        outcome: Outcome,
+    ) {
+        let timestamp = now();
+        let entry = |kind, account: &str, posting: Posting| JournalEntry {
+            transaction,
+            timestamp,
+            kind,
+            account: account.to_string(),
+            amount: posting.amount,
+            rate: posting.rate,
+            key: key.map(str::to_string),
+            outcome: outcome.clone(),
+        };
+        let entries = vec![
+            entry(EntryKind::Debit, from, postings.debit),
+            entry(EntryKind::Credit, to, postings.credit),
+        ];
+        if let Err(err) = self.record(entries) {
+            // the money already moved, verify will point out the missing entries
+            eprintln!(
+                "could not write transaction #{} to the journal: {}",
+                transaction, err
+            );
+        }
+    }
+
+    pub fn contains(&self, account: &str) -> bool {
+        self.accounts.contains_key(account)
+    }
+
+    /// Every account with its balance and the funds held by pending payments, by name.
+    pub fn balances(&self) -> Vec<(&str, Money, Option<Money>)> {
+        let mut balances: Vec<_> = self
+            .accounts
+            .iter()
+            .map(|(account, balance)| {
+                (account.as_str(), *balance, self.holds.get(account).copied())
+            })
+            .collect();
+        balances.sort_by_key(|(account, _, _)| *account);
+        balances
+    }
+
+    /// Issue a payment token for `account`, valid for `ttl` seconds.
+    pub fn issue_token(&mut self, account: &str, ttl: u64) -> io::Result<String> {
+        if !self.contains(account) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown account {}", account),
+            ));
+        }
+        self.vault.issue(account, ttl)
+    }
+
+    /// Revoke a payment token, returns whether the token was known.
+    pub fn revoke_token(&mut self, token: &str) -> io::Result<bool> {
+        self.vault.revoke(token)
+    }
+
+    /// Accounts behind the paying `token` and the destination, an account or a token.
+    fn resolve(&self, token: &str, to: &str) -> Result<(String, String), String> {
+        let from = self
+            .vault
+            .resolve(token, now())
+            .map_err(|err| err.to_string())?;
+        if !self.contains(from) {
+            return Err("Unknown client account".to_string());
+        }
+        let to = if self.contains(to) {
+            to
+        } else {
+            // crediting an account does not need a token that can still be charged
+            self.vault
+                .account(to)
+                .filter(|account| self.contains(account))
+                .ok_or_else(|| "Unknown destination account".to_string())?
+        };
+        Ok((from.to_string(), to.to_string()))
+    }
+
+    /// Account to journal for an account or token that may not be valid.
+    fn journal_account<'a>(&'a self, party: &'a str) -> &'a str {
+        if self.contains(party) {
+            party
+        } else {
+            self.vault.account(party).unwrap_or(UNKNOWN_ACCOUNT)
+        }
+    }
+
+    /// `amount` in `currency`, with the rate it was converted at.
+    fn convert(&self, amount: Money, currency: Currency) -> Result<Posting, String> {
+        if amount.currency() == currency {
+            return Ok(Posting { amount, rate: None });
+        }
+        let rate = self
+            .rates
+            .rate(amount.currency(), currency)
+            .ok_or_else(|| {
+                format!(
+                    "No exchange rate from {} to {}",
+                    amount.currency(),
+                    currency
+                )
+            })?;
+        let amount = amount
+            .convert(currency, rate)
+            .map_err(|err| err.to_string())?;
+        Ok(Posting {
+            amount,
+            rate: Some(rate),
+        })
+    }
+
+    /// What moving `amount` debits from `from` and credits to `to`, each in its own currency.
+    fn postings(&self, from: &str, to: &str, amount: Money) -> Result<Postings, String> {
+        Ok(Postings {
+            debit: self.convert(amount, self.accounts[from].currency())?,
+            credit: self.convert(amount, self.accounts[to].currency())?,
+        })
+    }
+
+    /// Balance that is not held by an authorization.
+    fn available(&self, account: &str) -> Result<Money, MoneyError> {
+        let balance = self.accounts[account];
+        match self.holds.get(account) {
+            Some(&held) => balance.checked_sub(held),
+            None => Ok(balance),
+        }
+    }
+
+    /// Whether `amount` can be taken from the available balance of `account`.
+    fn check_funds(&self, account: &str, amount: Money) -> Result<(), String> {
+        let left = self
+            .available(account)
+            .and_then(|available| available.checked_sub(amount))
+            .map_err(|err| err.to_string())?;
+        if left.is_negative() {
+            return Err("Insufficient funds".to_string());
+        }
+        Ok(())
+    }
+
+    /// Check a debit of `amount` from `account` against the [`RiskPolicy`].
+    ///
+    /// Limits are compared in the currency of the account.
+    fn assess(&self, account: &str, amount: Money) -> Result<Risk, String> {
+        if self.risk.trusted.iter().any(|trusted| trusted == account) {
+            return Ok(Risk::Pass);
+        }
+        let currency = self.accounts[account].currency();
+        let limit = |limit: Money| self.convert(limit, currency).map(|posting| posting.amount);
+        let amount = self.convert(amount, currency)?.amount;
+
+        let transaction_limit = limit(self.risk.transaction_limit)?;
+        if amount > transaction_limit {
+            return Ok(Risk::Reject(RiskReason::TransactionLimit {
+                amount,
+                limit: transaction_limit,
+            }));
+        }
+
+        let now = now();
+        let debits = || {
+            self.account_journal(account)
+                .filter(|entry| entry.kind == EntryKind::Debit)
+        };
+        let window_start = now.saturating_sub(self.risk.velocity_window);
+        let mut recent: Vec<_> = debits()
+            .filter(|entry| entry.timestamp >= window_start)
+            .map(|entry| entry.transaction)
+            .collect();
+        recent.dedup();
+        if recent.len() >= self.risk.velocity_count {
+            return Ok(Risk::Reject(RiskReason::Velocity {
+                count: recent.len(),
+                window: self.risk.velocity_window,
+            }));
+        }
+
+        let day_start = now.saturating_sub(24 * 60 * 60);
+        let held = self.holds.get(account).copied();
+        let spent = Money::sum(
+            currency,
+            debits()
+                .filter(|entry| entry.timestamp >= day_start)
+                .filter(|entry| entry.outcome == Outcome::Finished)
+                .map(|entry| entry.amount)
+                .chain(held),
+        )
+        .map_err(|err| err.to_string())?;
+        let daily_limit = limit(self.risk.daily_limit)?;
+        let total = spent.checked_add(amount).map_err(|err| err.to_string())?;
+        if total > daily_limit {
+            return Ok(Risk::Reject(RiskReason::DailyLimit {
+                spent,
+                amount,
+                limit: daily_limit,
+            }));
+        }
+
+        let threshold = limit(self.risk.review_threshold)?;
+        if amount > threshold {
+            return Ok(Risk::Review(RiskReason::LargeAmount { amount, threshold }));
+        }
+        Ok(Risk::Pass)
+    }
+
+    /// Whether holding `amount` more on `account` needs the client to pass a challenge.
+    fn needs_challenge(&self, account: &str, amount: Money) -> bool {
+        if self.risk.trusted.iter().any(|trusted| trusted == account) {
+            return false;
+        }
+        if let Some(&passed) = self.stepped_up.get(account) {
+            if passed + STEP_UP_TTL >= now() {
+                return false;
+            }
+        }
+        let currency = self.accounts[account].currency();
+        let held = self.holds.get(account).copied();
+        let total = self.convert(amount, currency).and_then(|amount| {
+            Money::sum(currency, held.into_iter().chain(Some(amount.amount)))
+                .map_err(|err| err.to_string())
+        });
+        let threshold = self
+            .convert(self.risk.challenge_threshold, currency)
+            .map(|posting| posting.amount);
+        match (total, threshold) {
+            (Ok(total), Ok(threshold)) => total > threshold,
+            // holding fails anyway and reports the error
+            _ => false,
+        }
+    }
+
+    /// Hold `amount` on `from` in the currency of the account, journaling the failure.
+    fn authorize(
+        &mut self,
+        transaction: u64,
+        key: Option<&str>,
+        from: &str,
+        to: &str,
+        amount: Money,
+    ) -> Result<Money, String> {
+        let currency = self.accounts[from].currency();
+        let result = self.convert(amount, currency).and_then(|held| {
+            self.hold(from, held.amount)?;
+            Ok(held.amount)
+        });
+        if let Err(message) = &result {
+            self.record_transaction(
+                transaction,
+                key,
+                from,
+                to,
+                Postings::unconverted(amount),
+                Outcome::Error(message.clone()),
+            );
+        }
+        result
+    }
+
+    fn hold(&mut self, account: &str, amount: Money) -> Result<(), String> {
+        self.check_funds(account, amount)?;
+        let held = match self.holds.get(account) {
+            Some(&held) => held.checked_add(amount).map_err(|err| err.to_string())?,
+            None => amount,
+        };
+        self.holds.insert(account.to_string(), held);
+        Ok(())
+    }
+
+    fn release(&mut self, account: &str, amount: Money) {
+        if let Some(held) = self.holds.get_mut(account) {
+            // holds only ever add up amounts that were checked
+            if let Ok(left) = held.checked_sub(amount) {
+                *held = left;
+            }
+            if held.minor() == 0 {
+                self.holds.remove(account);
+            }
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut accounts: Vec<_> = self.accounts.iter().collect();
+        accounts.sort_by_key(|(account, _)| *account);
+        let mut contents = String::new();
+        for (account, balance) in accounts {
+            contents.push_str(&format!("{},{}\n", account, balance));
+        }
+        file::write_atomic(path, contents)
+    }
+}
+
+/// Exchange rates between currencies.
+///
+/// Rate files are CSV with one `from,to,rate` line per pair, e.g. `EUR,GBP,0.85`.
+/// A pair without a rate is converted at the inverse of the opposite one, if known.
+#[derive(Clone, Debug, Default)]
+pub struct RateTable {
+    rates: HashMap<(Currency, Currency), Rate>,
+}
+
+impl RateTable {
+    /// Load the rates at `path`, a missing file only allows same currency transfers.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Self::parse(&contents),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn parse(contents: &str) -> io::Result<Self> {
+        let mut table = Self::default();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || (i == 0 && line == "from,to,rate") {
+                continue;
+            }
+            let invalid = |message: String| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("rates line {}: {}", i + 1, message),
+                )
+            };
+            let fields: Vec<_> = line.split(',').map(str::trim).collect();
+            if fields.len() != 3 {
+                return Err(invalid("expected from,to,rate".to_string()));
+            }
+            let parse_error = |err: MoneyError| invalid(err.to_string());
+            let from = fields[0].parse().map_err(parse_error)?;
+            let to = fields[1].parse().map_err(parse_error)?;
+            let rate = fields[2].parse().map_err(parse_error)?;
+            table.insert(from, to, rate);
+        }
+        Ok(table)
+    }
+
+    pub fn insert(&mut self, from: Currency, to: Currency, rate: Rate) {
+        self.rates.insert((from, to), rate);
+    }
+
+    pub fn rate(&self, from: Currency, to: Currency) -> Option<Rate> {
+        if from == to {
+            return Some(Rate::ONE);
+        }
+        self.rates
+            .get(&(from, to))
+            .copied()
+            .or_else(|| self.rates.get(&(to, from)).map(|rate| rate.inverse()))
+    }
+}
+
+/// One side of a transaction, in the currency of its account.
+#[derive(Clone, Copy, Debug)]
+struct Posting {
+    amount: Money,
+    rate: Option<Rate>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Postings {
+    debit: Posting,
+    credit: Posting,
+}
+
+impl Postings {
+    /// Both sides at the transaction amount, for transactions that did not go through.
+    fn unconverted(amount: Money) -> Self {
+        let posting = Posting { amount, rate: None };
+        Self {
+            debit: posting,
+            credit: posting,
+        }
+    }
+}
+
+/// Limits on what a client account may spend, checked before every transaction.
+///
+/// Limits are given in one currency and converted to the currency of the account.
+#[derive(Clone, Debug)]
+pub struct RiskPolicy {
+    /// Largest single transaction.
+    pub transaction_limit: Money,
+    /// Transactions above this amount wait for a manual review.
+    pub review_threshold: Money,
+    /// Most that can be spent, or held, over the last 24 hours.
+    pub daily_limit: Money,
+    /// Most transactions allowed within `velocity_window` seconds.
+    pub velocity_count: usize,
+    pub velocity_window: u64,
+    /// Authorizations that take the funds held on an account above this amount,
+    /// e.g. for a large purchase, are confirmed with a one-time code.
+    pub challenge_threshold: Money,
+    /// Accounts that are never checked, e.g. the merchant paying refunds.
+    pub trusted: Vec<String>,
+}
+
+impl Default for RiskPolicy {
+    fn default() -> Self {
+        Self {
+            transaction_limit: Money::new(500_000, Currency::EUR),
+            review_threshold: Money::new(200_000, Currency::EUR),
+            daily_limit: Money::new(1_000_000, Currency::EUR),
+            velocity_count: 10,
+            velocity_window: 60,
+            challenge_threshold: Money::new(100_000, Currency::EUR),
+            trusted: vec!["travel_agency".to_string()],
+        }
+    }
+}
+
+/// Why the risk checks stopped a transaction or sent it to review.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RiskReason {
+    TransactionLimit {
+        amount: Money,
+        limit: Money,
+    },
+    DailyLimit {
+        spent: Money,
+        amount: Money,
+        limit: Money,
+    },
+    Velocity {
+        count: usize,
+        window: u64,
+    },
+    LargeAmount {
+        amount: Money,
+        threshold: Money,
+    },
+}
+
+impl fmt::Display for RiskReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RiskReason::TransactionLimit { amount, limit } => write!(
+                f,
+                "Transaction limit exceeded: {} is over the limit of {}",
+                amount, limit
+            ),
+            RiskReason::DailyLimit {
+                spent,
+                amount,
+                limit,
+            } => write!(
+                f,
+                "Daily limit exceeded: {} on top of {} today is over the limit of {}",
+                amount, spent, limit
+            ),
+            RiskReason::Velocity { count, window } => write!(
+                f,
+                "Too many transactions: {} in the last {} seconds",
+                count, window
+            ),
+            RiskReason::LargeAmount { amount, threshold } => write!(
+                f,
+                "Large amount: {} is over the review threshold of {}",
+                amount, threshold
+            ),
+        }
+    }
+}
+
+/// Result of [`Bank::assess`].
+#[derive(Clone, Debug)]
+enum Risk {
+    Pass,
+    Review(RiskReason),
+    Reject(RiskReason),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TokenError {
+    Unknown,
+    Expired,
+    Revoked,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Unknown => write!(f, "Unknown payment token"),
+            TokenError::Expired => write!(f, "Expired payment token"),
+            TokenError::Revoked => write!(f, "Revoked payment token"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct PaymentToken {
+    account: String,
+    /// Seconds since the UNIX epoch.
+    expires_at: u64,
+    revoked: bool,
+}
+
+/// Opaque payment tokens, each bound to an account.
+///
+/// Vaults opened from a file are saved whenever a token is issued or revoked,
+/// one `token,account,expires_at,revoked` line per token.
+#[derive(Debug, Default)]
+pub struct TokenVault {
+    tokens: HashMap<String, PaymentToken>,
+    path: Option<PathBuf>,
+}
+
+impl TokenVault {
+    /// Open the vault at `path`, a missing file starts an empty vault.
+    fn open(path: PathBuf) -> io::Result<Self> {
+        let mut tokens = HashMap::new();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        for (i, line) in contents.lines().enumerate() {
+            let mut fields = line.split(',');
+            let token = (|| {
+                let token = fields.next()?.to_string();
+                let account = fields.next()?.to_string();
+                let expires_at = fields.next()?.parse().ok()?;
+                let revoked = fields.next()?.parse().ok()?;
+                Some((
+                    token,
+                    PaymentToken {
+                        account,
+                        expires_at,
+                        revoked,
+                    },
+                ))
+            })();
+            let (token, payment_token) = token.ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("tokens line {}: invalid token", i + 1),
+                )
+            })?;
+            tokens.insert(token, payment_token);
+        }
+        Ok(Self {
+            tokens,
+            path: Some(path),
+        })
+    }
+
+    fn issue(&mut self, account: &str, ttl: u64) -> io::Result<String> {
+        let now = now();
+        self.tokens
+            .retain(|_, token| token.expires_at + TOKEN_RETENTION > now);
+        let token = new_token();
+        self.tokens.insert(
+            token.clone(),
+            PaymentToken {
+                account: account.to_string(),
+                expires_at: now + ttl,
+                revoked: false,
+            },
+        );
+        self.save()?;
+        Ok(token)
+    }
+
+    fn revoke(&mut self, token: &str) -> io::Result<bool> {
+        match self.tokens.get_mut(token) {
+            Some(payment_token) => {
+                payment_token.revoked = true;
+                self.save()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Account that can be charged with `token` at `now`.
+    fn resolve(&self, token: &str, now: u64) -> Result<&str, TokenError> {
+        let payment_token = self.tokens.get(token).ok_or(TokenError::Unknown)?;
+        if payment_token.revoked {
+            Err(TokenError::Revoked)
+        } else if payment_token.expires_at <= now {
+            Err(TokenError::Expired)
+        } else {
+            Ok(&payment_token.account)
+        }
+    }
+
+    /// Account `token` is bound to, whether it can still be charged or not.
+    fn account(&self, token: &str) -> Option<&str> {
+        self.tokens
+            .get(token)
+            .map(|payment_token| payment_token.account.as_str())
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut tokens: Vec<_> = self.tokens.iter().collect();
+        tokens.sort_by_key(|(token, _)| token.as_str());
+        let mut contents = String::new();
+        for (token, payment_token) in tokens {
+            contents.push_str(&format!(
+                "{},{},{},{}\n",
+                token, payment_token.account, payment_token.expires_at, payment_token.revoked
+            ));
+        }
+        file::write_atomic(path, contents)
+    }
+}
+
+/// A token that cannot be guessed, 128 random bits from the operating system.
+fn new_token() -> String {
+    let mut bytes = [0; 16];
+    OsRng.fill_bytes(&mut bytes);
+    let mut token = TOKEN_PREFIX.to_string();
+    for byte in bytes {
+        token.push_str(&format!("{:02x}", byte));
+    }
+    token
+}
+
+/// Six random digits the client confirms a challenge with.
+fn new_challenge_code() -> String {
+    format!("{:06}", OsRng.gen_range(0..1_000_000))
+}
+
+fn demo_accounts() -> HashMap<String, Money> {
+    let mut accounts = HashMap::new();
+    accounts.insert(
+        "valid_client".to_string(),
+        Money::new(500_000, Currency::EUR),
+    );
+    accounts.insert(
+        "travel_agency".to_string(),
+        Money::new(5_000_000, Currency::EUR),
+    );
+    accounts.insert("gbp_client".to_string(), Money::new(400_000, Currency::GBP));
+    accounts.insert("usd_client".to_string(), Money::new(600_000, Currency::USD));
+    accounts
+}
+
+/// Whether a finished transaction debits as much as it credits,
+/// at the recorded rate when the accounts are in different currencies.
+fn balanced(entries: &[&JournalEntry]) -> Result<(), String> {
+    let currency = entries[0].amount.currency();
+    if entries
+        .iter()
+        .all(|entry| entry.amount.currency() == currency)
+    {
+        let effects = entries
+            .iter()
+            .map(|entry| entry.effect())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| err.to_string())?;
+        let sum = Money::sum(currency, effects).map_err(|err| err.to_string())?;
+        if sum.minor() != 0 {
+            return Err(format!("is off by {}", sum));
+        }
+        return Ok(());
+    }
+    let find = |kind| entries.iter().find(|entry| entry.kind == kind);
+    let (debit, credit) = match (
+        entries.len(),
+        find(EntryKind::Debit),
+        find(EntryKind::Credit),
+    ) {
+        (2, Some(debit), Some(credit)) => (debit, credit),
+        _ => return Err("mixes currencies".to_string()),
+    };
+    // one side is the transaction amount and the other one was converted from it
+    let converted = match (debit.rate, credit.rate) {
+        (Some(rate), None) => credit.amount.convert(debit.amount.currency(), rate),
+        (None, Some(rate)) => debit.amount.convert(credit.amount.currency(), rate),
+        // both converted from a third currency, there is nothing to compare
+        (Some(_), Some(_)) => return Ok(()),
+        (None, None) => return Err("mixes currencies without a rate".to_string()),
+    };
+    let expected = if debit.rate.is_some() {
+        debit.amount
+    } else {
+        credit.amount
+    };
+    match converted {
+        Ok(converted) if converted == expected => Ok(()),
+        _ => Err("does not match its exchange rate".to_string()),
+    }
+}
+
+fn accumulate<K>(
+    entry: std::collections::btree_map::Entry<'_, K, Money>,
+    amount: Money,
+) -> Result<(), MoneyError>
+where
+    K: Ord,
+{
+    let total = entry.or_insert(Money::zero(amount.currency()));
+    *total = total.checked_add(amount)?;
+    Ok(())
+}
+
+fn parse_journal(contents: &str) -> io::Result<Vec<JournalEntry>> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            JournalEntry::from_line(line).ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("journal line {}: invalid entry", i + 1),
+                )
+            })
+        })
+        .collect()
+}
+
+fn parse_accounts(contents: &str) -> io::Result<HashMap<String, Money>> {
+    let mut accounts = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let invalid = |message: &str| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("ledger line {}: {}", i + 1, message),
+            )
+        };
+        let (account, balance) = line
+            .split_once(',')
+            .ok_or_else(|| invalid("expected account,balance"))?;
+        let balance = balance
+            .parse::<Money>()
+            .map_err(|err| invalid(&err.to_string()))?;
+        accounts.insert(account.trim().to_string(), balance);
+    }
+    Ok(accounts)
+}
+
 #[typestate(enumerate, state_constructors)]
 pub mod bank_api {
-    use std::collections::HashMap;
+    use super::{Bank, RiskReason};
+    use std::{cell::RefCell, rc::Rc};
+    use travel_agency_common::money::Money;
 
     #[automata]
     pub struct Transaction {
-        pub accounts: HashMap<String, isize>,
+        pub bank: Rc<RefCell<Bank>>,
+        /// Identifies the transaction in the journal.
+        pub id: u64,
+        /// Repeating a request with the same key returns the original result.
+        pub key: Option<String>,
     }
 
+    /// `from` is the payment token of the paying account,
+    /// `to` is the destination account or a token bound to it.
     #[state]
     pub struct AccountValidation {
         pub from: String,
         pub to: String,
-        pub amount: isize,
+        pub amount: Money,
     }
 
     pub trait AccountValidation {
-        fn start_transaction(from: &str, to: &str, amount: isize) -> AccountValidation;
+        fn start_transaction(
+            bank: Rc<RefCell<Bank>>,
+            key: Option<&str>,
+            from: &str,
+            to: &str,
+            amount: Money,
+        ) -> AccountValidation;
         fn validate_accounts(self) -> AccountValidationResult;
     }
 
+    /// `Finish` when a transaction with the same key already finished.
     pub enum AccountValidationResult {
+        RiskCheck,
+        Error,
+        Finish,
+    }
+
+    /// The accounts exist, the spending limits of the client are checked next.
+    #[state]
+    pub struct RiskCheck {
+        pub from: String,
+        pub to: String,
+        pub amount: Money,
+    }
+
+    pub trait RiskCheck {
+        fn check_risk(self) -> RiskResult;
+    }
+
+    pub enum RiskResult {
         Valid,
+        Review,
         Error,
     }
 
+    /// The transaction goes through only once someone approves it.
+    #[state]
+    pub struct Review {
+        pub from: String,
+        pub to: String,
+        pub amount: Money,
+        pub reason: RiskReason,
+    }
+
+    pub trait Review {
+        fn approve(self) -> Valid;
+        fn reject(self) -> Error;
+        /// Give up the transaction before anyone reviewed it.
+        fn cancel(self);
+    }
+
     #[state]
     pub struct Error {
         pub message: String,
+        /// Set when the transaction was stopped by the risk checks.
+        pub risk: Option<RiskReason>,
     }
 
     pub trait Error {
@@ -41,11 +1223,57 @@ pub mod bank_api {
     pub struct Valid {
         pub from: String,
         pub to: String,
-        pub amount: isize,
+        pub amount: Money,
     }
 
     pub trait Valid {
         fn perform_transaction(self) -> TransactionResult;
+        fn authorize(self) -> AuthorizationResult;
+    }
+
+    pub enum AuthorizationResult {
+        Authorized,
+        Challenge,
+        Error,
+    }
+
+    /// The client confirms the payment with a one-time code before funds are held.
+    #[state]
+    pub struct Challenge {
+        pub from: String,
+        pub to: String,
+        pub amount: Money,
+        /// Sent to the client, in this demo by showing it.
+        pub code: String,
+        /// Wrong codes left before the transaction fails.
+        pub attempts: u32,
+    }
+
+    pub trait Challenge {
+        fn confirm(self, code: &str) -> ChallengeResult;
+        fn cancel(self);
+    }
+
+    pub enum ChallengeResult {
+        Authorized,
+        Challenge,
+        Error,
+    }
+
+    /// Funds are held on the client account until they are captured or voided.
+    #[state]
+    pub struct Authorized {
+        pub from: String,
+        pub to: String,
+        pub amount: Money,
+        /// The amount in the currency of the client account.
+        pub held: Money,
+    }
+
+    pub trait Authorized {
+        /// Move up to the authorized amount and release the rest of the hold.
+        fn capture(self, amount: Money) -> TransactionResult;
+        fn void(self);
     }
 
     pub enum TransactionResult {
@@ -62,58 +1290,414 @@ pub mod bank_api {
 }
 
 impl AccountValidationState for Transaction<AccountValidation> {
-    fn start_transaction(from: &str, to: &str, amount: isize) -> Transaction<AccountValidation> {
-        // pretend that the accounts are the connection to a DB
-        let mut accounts = HashMap::new();
-        accounts.insert("valid_client".to_string(), 5000);
-        accounts.insert("travel_agency".to_string(), 50000);
+    fn start_transaction(
+        bank: Rc<RefCell<Bank>>,
+        key: Option<&str>,
+        from: &str,
+        to: &str,
+        amount: Money,
+    ) -> Transaction<AccountValidation> {
+        let id = bank.borrow_mut().next_transaction();
         Self {
-            accounts,
+            bank,
+            id,
+            key: key.map(str::to_string),
             state: AccountValidation::new_state(from.to_string(), to.to_string(), amount),
         }
     }
     fn validate_accounts(self) -> AccountValidationResult {
-        if !self.accounts.contains_key(&self.state.from) {
-            AccountValidationResult::Error(Transaction::<Error> {
-                accounts: self.accounts,
-                state: Error::new_state("Unknown client account".to_string()),
-            })
-        } else if !self.accounts.contains_key(&self.state.to) {
-            AccountValidationResult::Error(Transaction::<Error> {
-                accounts: self.accounts,
-                state: Error::new_state("Unknown destination account".to_string()),
-            })
-        } else {
-            AccountValidationResult::Valid(Transaction::<Valid> {
-                accounts: self.accounts,
-                state: Valid::new_state(self.state.from, self.state.to, self.state.amount),
-            })
+        let settled = self
+            .key
+            .as_ref()
+            .and_then(|key| self.bank.borrow().settled(key));
+        if let Some(id) = settled {
+            // the money already moved, answer with the original transaction
+            return AccountValidationResult::Finish(Transaction::<Finish> {
+                bank: self.bank,
+                id,
+                key: self.key,
+                state: Finish,
+            });
         }
+        let resolved = {
+            let bank = self.bank.borrow();
+            if self
+                .key
+                .as_ref()
+                .is_some_and(|key| key.is_empty() || key.contains([',', '\n']))
+            {
+                // the key is a journal field
+                Err("Invalid idempotency key".to_string())
+            } else {
+                bank.resolve(&self.state.from, &self.state.to)
+            }
+        };
+        match resolved {
+            Err(message) => {
+                let mut bank = self.bank.borrow_mut();
+                // tokens never make it to the journal
+                let from = bank.journal_account(&self.state.from).to_string();
+                let to = bank.journal_account(&self.state.to).to_string();
+                bank.record_transaction(
+                    self.id,
+                    self.key.as_deref(),
+                    &from,
+                    &to,
+                    Postings::unconverted(self.state.amount),
+                    Outcome::Error(message.clone()),
+                );
+                drop(bank);
+                AccountValidationResult::Error(Transaction::<Error> {
+                    bank: self.bank,
+                    id: self.id,
+                    key: self.key,
+                    state: Error::new_state(message, None),
+                })
+            }
+            Ok((from, to)) => AccountValidationResult::RiskCheck(Transaction::<RiskCheck> {
+                bank: self.bank,
+                id: self.id,
+                key: self.key,
+                state: RiskCheck::new_state(from, to, self.state.amount),
+            }),
+        }
+    }
+}
+
+impl RiskCheckState for Transaction<RiskCheck> {
+    fn check_risk(self) -> RiskResult {
+        let assessed = self
+            .bank
+            .borrow()
+            .assess(&self.state.from, self.state.amount);
+        let (message, risk) = match assessed {
+            Ok(Risk::Pass) => {
+                return RiskResult::Valid(Transaction::<Valid> {
+                    bank: self.bank,
+                    id: self.id,
+                    key: self.key,
+                    state: Valid::new_state(self.state.from, self.state.to, self.state.amount),
+                })
+            }
+            Ok(Risk::Review(reason)) => {
+                return RiskResult::Review(Transaction::<Review> {
+                    bank: self.bank,
+                    id: self.id,
+                    key: self.key,
+                    state: Review::new_state(
+                        self.state.from,
+                        self.state.to,
+                        self.state.amount,
+                        reason,
+                    ),
+                })
+            }
+            Ok(Risk::Reject(reason)) => (reason.to_string(), Some(reason)),
+            Err(message) => (message, None),
+        };
+        self.bank.borrow_mut().record_transaction(
+            self.id,
+            self.key.as_deref(),
+            &self.state.from,
+            &self.state.to,
+            Postings::unconverted(self.state.amount),
+            Outcome::Error(message.clone()),
+        );
+        RiskResult::Error(Transaction::<Error> {
+            bank: self.bank,
+            id: self.id,
+            key: self.key,
+            state: Error::new_state(message, risk),
+        })
+    }
+}
+
+impl ReviewState for Transaction<Review> {
+    fn approve(self) -> Transaction<Valid> {
+        Transaction::<Valid> {
+            bank: self.bank,
+            id: self.id,
+            key: self.key,
+            state: Valid::new_state(self.state.from, self.state.to, self.state.amount),
+        }
+    }
+    fn reject(self) -> Transaction<Error> {
+        let message = format!("Rejected after review: {}", self.state.reason);
+        self.bank.borrow_mut().record_transaction(
+            self.id,
+            self.key.as_deref(),
+            &self.state.from,
+            &self.state.to,
+            Postings::unconverted(self.state.amount),
+            Outcome::Error(message.clone()),
+        );
+        Transaction::<Error> {
+            bank: self.bank,
+            id: self.id,
+            key: self.key,
+            state: Error::new_state(message, Some(self.state.reason)),
+        }
+    }
+    fn cancel(self) {
+        self.bank.borrow_mut().record_transaction(
+            self.id,
+            self.key.as_deref(),
+            &self.state.from,
+            &self.state.to,
+            Postings::unconverted(self.state.amount),
+            Outcome::Error("Review cancelled".to_string()),
+        );
     }
 }
 
 impl ValidState for Transaction<Valid> {
-    fn perform_transaction(mut self) -> TransactionResult {
-        let accounts = &mut self.accounts;
-        // safe unwraps
-        let client_balance = accounts.get_mut(&self.state.from).unwrap();
-        let amount = self.state.amount;
-        if *client_balance - amount < 0 {
-            return TransactionResult::Error(Transaction::<Error> {
-                accounts: self.accounts,
-                state: Error::new_state("Insufficient funds".to_string()),
+    fn perform_transaction(self) -> TransactionResult {
+        let result = transfer_and_record(
+            &mut self.bank.borrow_mut(),
+            self.id,
+            self.key.as_deref(),
+            &self.state.from,
+            &self.state.to,
+            self.state.amount,
+        );
+        match result {
+            Ok(()) => TransactionResult::Finish(Transaction::<Finish> {
+                bank: self.bank,
+                id: self.id,
+                key: self.key,
+                state: Finish,
+            }),
+            Err(message) => TransactionResult::Error(Transaction::<Error> {
+                bank: self.bank,
+                id: self.id,
+                key: self.key,
+                state: Error::new_state(message, None),
+            }),
+        }
+    }
+    fn authorize(self) -> AuthorizationResult {
+        let challenged = self
+            .bank
+            .borrow()
+            .needs_challenge(&self.state.from, self.state.amount);
+        if challenged {
+            return AuthorizationResult::Challenge(Transaction::<Challenge> {
+                bank: self.bank,
+                id: self.id,
+                key: self.key,
+                state: Challenge::new_state(
+                    self.state.from,
+                    self.state.to,
+                    self.state.amount,
+                    new_challenge_code(),
+                    CHALLENGE_ATTEMPTS,
+                ),
             });
         }
+        let result = self.bank.borrow_mut().authorize(
+            self.id,
+            self.key.as_deref(),
+            &self.state.from,
+            &self.state.to,
+            self.state.amount,
+        );
+        match result {
+            Ok(held) => AuthorizationResult::Authorized(Transaction::<Authorized> {
+                bank: self.bank,
+                id: self.id,
+                key: self.key,
+                state: Authorized::new_state(
+                    self.state.from,
+                    self.state.to,
+                    self.state.amount,
+                    held,
+                ),
+            }),
+            Err(message) => AuthorizationResult::Error(Transaction::<Error> {
+                bank: self.bank,
+                id: self.id,
+                key: self.key,
+                state: Error::new_state(message, None),
+            }),
+        }
+    }
+}
 
-        *client_balance -= amount;
+impl ChallengeState for Transaction<Challenge> {
+    fn confirm(mut self, code: &str) -> ChallengeResult {
+        if code != self.state.code {
+            self.state.attempts -= 1;
+            if self.state.attempts > 0 {
+                return ChallengeResult::Challenge(self);
+            }
+            let message = "Too many wrong confirmation codes".to_string();
+            self.bank.borrow_mut().record_transaction(
+                self.id,
+                self.key.as_deref(),
+                &self.state.from,
+                &self.state.to,
+                Postings::unconverted(self.state.amount),
+                Outcome::Error(message.clone()),
+            );
+            return ChallengeResult::Error(Transaction::<Error> {
+                bank: self.bank,
+                id: self.id,
+                key: self.key,
+                state: Error::new_state(message, None),
+            });
+        }
+        let result = {
+            let mut bank = self.bank.borrow_mut();
+            bank.stepped_up.insert(self.state.from.clone(), now());
+            bank.authorize(
+                self.id,
+                self.key.as_deref(),
+                &self.state.from,
+                &self.state.to,
+                self.state.amount,
+            )
+        };
+        match result {
+            Ok(held) => ChallengeResult::Authorized(Transaction::<Authorized> {
+                bank: self.bank,
+                id: self.id,
+                key: self.key,
+                state: Authorized::new_state(
+                    self.state.from,
+                    self.state.to,
+                    self.state.amount,
+                    held,
+                ),
+            }),
+            Err(message) => ChallengeResult::Error(Transaction::<Error> {
+                bank: self.bank,
+                id: self.id,
+                key: self.key,
+                state: Error::new_state(message, None),
+            }),
+        }
+    }
+    fn cancel(self) {
+        self.bank.borrow_mut().record_transaction(
+            self.id,
+            self.key.as_deref(),
+            &self.state.from,
+            &self.state.to,
+            Postings::unconverted(self.state.amount),
+            Outcome::Error("Challenge cancelled".to_string()),
+        );
+    }
+}
 
-        let destination_balance = accounts.get_mut(&self.state.to).unwrap();
-        *destination_balance += amount;
-        TransactionResult::Finish(Transaction::<Finish> {
-            accounts: self.accounts,
-            state: Finish,
-        })
+impl AuthorizedState for Transaction<Authorized> {
+    fn capture(self, amount: Money) -> TransactionResult {
+        let result = {
+            let mut bank = self.bank.borrow_mut();
+            bank.release(&self.state.from, self.state.held);
+            // false for another currency
+            let within = amount <= self.state.amount;
+            if amount.is_negative() || !within {
+                let message = format!(
+                    "Cannot capture {} of an authorization of {}",
+                    amount, self.state.amount
+                );
+                bank.record_transaction(
+                    self.id,
+                    self.key.as_deref(),
+                    &self.state.from,
+                    &self.state.to,
+                    Postings::unconverted(amount),
+                    Outcome::Error(message.clone()),
+                );
+                Err(message)
+            } else {
+                transfer_and_record(
+                    &mut bank,
+                    self.id,
+                    self.key.as_deref(),
+                    &self.state.from,
+                    &self.state.to,
+                    amount,
+                )
+            }
+        };
+        match result {
+            Ok(()) => TransactionResult::Finish(Transaction::<Finish> {
+                bank: self.bank,
+                id: self.id,
+                key: self.key,
+                state: Finish,
+            }),
+            Err(message) => TransactionResult::Error(Transaction::<Error> {
+                bank: self.bank,
+                id: self.id,
+                key: self.key,
+                state: Error::new_state(message, None),
+            }),
+        }
+    }
+    fn void(self) {
+        self.bank
+            .borrow_mut()
+            .release(&self.state.from, self.state.held);
+    }
+}
+
+/// Move the funds, converted to the currency of each account, and journal the outcome.
+fn transfer_and_record(
+    bank: &mut Bank,
+    id: u64,
+    key: Option<&str>,
+    from: &str,
+    to: &str,
+    amount: Money,
+) -> Result<(), String> {
+    let result = bank.postings(from, to, amount).and_then(|postings| {
+        move_funds(bank, from, to, postings)?;
+        Ok(postings)
+    });
+    match result {
+        Ok(postings) => {
+            bank.record_transaction(id, key, from, to, postings, Outcome::Finished);
+            Ok(())
+        }
+        Err(message) => {
+            let outcome = Outcome::Error(message.clone());
+            bank.record_transaction(id, key, from, to, Postings::unconverted(amount), outcome);
+            Err(message)
+        }
+    }
+}
+
+fn move_funds(bank: &mut Bank, from: &str, to: &str, postings: Postings) -> Result<(), String> {
+    let (debit, credit) = (postings.debit.amount, postings.credit.amount);
+    bank.check_funds(from, debit)?;
+    // safe indexing, the accounts were validated
+    let (from_balance, to_balance) = (bank.accounts[from], bank.accounts[to]);
+    let restore = |bank: &mut Bank| {
+        bank.accounts.insert(from.to_string(), from_balance);
+        bank.accounts.insert(to.to_string(), to_balance);
+    };
+    let debited = from_balance
+        .checked_sub(debit)
+        .map_err(|err| err.to_string())?;
+    bank.accounts.insert(from.to_string(), debited);
+    // read again, `from` and `to` may be the same account
+    match bank.accounts[to].checked_add(credit) {
+        Ok(credited) => {
+            bank.accounts.insert(to.to_string(), credited);
+        }
+        Err(err) => {
+            restore(bank);
+            return Err(err.to_string());
+        }
+    }
+    if let Err(err) = bank.save() {
+        // undo the transfer, the ledger on disk is the source of truth
+        restore(bank);
+        return Err(format!("Could not save the ledger: {}", err));
     }
+    Ok(())
 }
 
 impl ErrorState for Transaction<Error> {
@@ -127,3 +1711,572 @@ impl FinishState for Transaction<Finish> {
         // consume
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    const CLIENT: &str = "valid_client";
+    const AGENCY: &str = "travel_agency";
+
+    /// A ledger with the demo accounts in a file of its own.
+    fn open_bank(name: &str) -> (Rc<RefCell<Bank>>, PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("travel-agency-{}-{}.csv", name, process::id()));
+        remove_bank(&path);
+        let bank = Bank::open(&path).unwrap();
+        (Rc::new(RefCell::new(bank)), path)
+    }
+
+    fn remove_bank(path: &Path) {
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(path.with_extension("journal"));
+        let _ = fs::remove_file(path.with_extension("tokens"));
+    }
+
+    fn eur(major: i64) -> Money {
+        Money::from_major(major, Currency::EUR).unwrap()
+    }
+
+    fn balance(bank: &Rc<RefCell<Bank>>, account: &str) -> Money {
+        bank.borrow().accounts[account]
+    }
+
+    fn client_token(bank: &Rc<RefCell<Bank>>) -> String {
+        bank.borrow_mut().issue_token(CLIENT, TOKEN_TTL).unwrap()
+    }
+
+    /// Authorize and capture `amount`, like a purchase does.
+    fn pay(bank: &Rc<RefCell<Bank>>, key: &str, amount: i64) -> Result<u64, String> {
+        pay_with(bank, &client_token(bank), key, amount)
+    }
+
+    fn pay_with(
+        bank: &Rc<RefCell<Bank>>,
+        token: &str,
+        key: &str,
+        amount: i64,
+    ) -> Result<u64, String> {
+        let amount = eur(amount);
+        let transaction = Transaction::<AccountValidation>::start_transaction(
+            bank.clone(),
+            Some(key),
+            token,
+            AGENCY,
+            amount,
+        );
+        let result = match transaction.validate_accounts() {
+            AccountValidationResult::RiskCheck(checked) => match approved(checked) {
+                Ok(valid) => match valid.authorize() {
+                    AuthorizationResult::Authorized(authorized) => authorized.capture(amount),
+                    AuthorizationResult::Challenge(challenge) => {
+                        // the client confirms with the code the bank sent
+                        let code = challenge.state.code.clone();
+                        match challenge.confirm(&code) {
+                            ChallengeResult::Authorized(authorized) => authorized.capture(amount),
+                            ChallengeResult::Challenge(_) => panic!("the code was rejected"),
+                            ChallengeResult::Error(error) => TransactionResult::Error(error),
+                        }
+                    }
+                    AuthorizationResult::Error(error) => TransactionResult::Error(error),
+                },
+                Err(error) => TransactionResult::Error(error),
+            },
+            AccountValidationResult::Error(error) => TransactionResult::Error(error),
+            AccountValidationResult::Finish(finish) => TransactionResult::Finish(finish),
+        };
+        match result {
+            TransactionResult::Finish(finish) => Ok(finish.id),
+            TransactionResult::Error(error) => Err(error.state.message),
+        }
+    }
+
+    /// Run the risk checks, approving the transactions sent to review.
+    fn approved(checked: Transaction<RiskCheck>) -> Result<Transaction<Valid>, Transaction<Error>> {
+        match checked.check_risk() {
+            RiskResult::Valid(valid) => Ok(valid),
+            RiskResult::Review(review) => Ok(review.approve()),
+            RiskResult::Error(error) => Err(error),
+        }
+    }
+
+    /// Start a payment of `amount` and stop after the risk checks.
+    fn check(bank: &Rc<RefCell<Bank>>, key: &str, amount: Money) -> RiskResult {
+        let transaction = Transaction::<AccountValidation>::start_transaction(
+            bank.clone(),
+            Some(key),
+            &client_token(bank),
+            AGENCY,
+            amount,
+        );
+        match transaction.validate_accounts() {
+            AccountValidationResult::RiskCheck(checked) => checked.check_risk(),
+            AccountValidationResult::Error(error) => panic!("{}", error.state.message),
+            AccountValidationResult::Finish(_) => panic!("{} is already settled", key),
+        }
+    }
+
+    fn risk_error(result: RiskResult) -> Transaction<Error> {
+        match result {
+            RiskResult::Error(error) => error,
+            RiskResult::Valid(_) => panic!("transaction passed the risk checks"),
+            RiskResult::Review(review) => {
+                panic!("transaction sent to review: {}", review.state.reason)
+            }
+        }
+    }
+
+    #[test]
+    fn repeated_key_returns_original_transaction() {
+        let (bank, path) = open_bank("repeated-key");
+        let first = pay(&bank, "order-1", 100).unwrap();
+        let second = pay(&bank, "order-1", 100).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(balance(&bank, CLIENT), eur(4900));
+        assert_eq!(balance(&bank, AGENCY), eur(50100));
+        assert!(bank.borrow().verify().is_ok());
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn repeated_key_skips_perform_transaction() {
+        let (bank, path) = open_bank("repeated-perform");
+        let token = client_token(&bank);
+        for _ in 0..2 {
+            let transaction = Transaction::<AccountValidation>::start_transaction(
+                bank.clone(),
+                Some("transfer-1"),
+                &token,
+                AGENCY,
+                eur(300),
+            );
+            match transaction.validate_accounts() {
+                AccountValidationResult::RiskCheck(checked) => match approved(checked) {
+                    Ok(valid) => match valid.perform_transaction() {
+                        TransactionResult::Finish(finish) => finish.finish(),
+                        TransactionResult::Error(error) => panic!("{}", error.state.message),
+                    },
+                    Err(error) => panic!("{}", error.state.message),
+                },
+                AccountValidationResult::Finish(finish) => finish.finish(),
+                AccountValidationResult::Error(error) => panic!("{}", error.state.message),
+            }
+        }
+        assert_eq!(balance(&bank, CLIENT), eur(4700));
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn retry_after_partial_failure_charges_only_the_rest() {
+        let (bank, path) = open_bank("partial-failure");
+        assert!(pay(&bank, "line-1", 1000).is_ok());
+        assert_eq!(
+            pay(&bank, "line-2", 4500),
+            Err("Insufficient funds".to_string())
+        );
+        assert_eq!(balance(&bank, CLIENT), eur(4000));
+
+        // the client tops up the account and the whole purchase is retried
+        bank.borrow_mut()
+            .accounts
+            .insert(CLIENT.to_string(), eur(5000));
+        assert!(pay(&bank, "line-1", 1000).is_ok());
+        assert!(pay(&bank, "line-2", 4500).is_ok());
+        assert_eq!(balance(&bank, CLIENT), eur(500));
+        assert_eq!(balance(&bank, AGENCY), eur(55500));
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn failed_transaction_does_not_settle_its_key() {
+        let (bank, path) = open_bank("failed-key");
+        assert!(pay(&bank, "line-1", 6000).is_err());
+        assert_eq!(bank.borrow().settled("line-1"), None);
+        assert!(pay(&bank, "line-1", 600).is_ok());
+        assert_eq!(balance(&bank, CLIENT), eur(4400));
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn keys_survive_reopening_the_ledger() {
+        let (bank, path) = open_bank("reopen");
+        let first = pay(&bank, "line-1", 250).unwrap();
+        drop(bank);
+        let bank = Rc::new(RefCell::new(Bank::open(&path).unwrap()));
+        assert_eq!(pay(&bank, "line-1", 250), Ok(first));
+        assert_eq!(balance(&bank, CLIENT), eur(4750));
+        assert!(bank.borrow().verify().is_ok());
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn keys_must_fit_in_the_journal() {
+        let (bank, path) = open_bank("invalid-key");
+        assert_eq!(
+            pay(&bank, "a,b", 10),
+            Err("Invalid idempotency key".to_string())
+        );
+        assert_eq!(balance(&bank, CLIENT), eur(5000));
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn tokens_are_bound_to_their_account() {
+        let (bank, path) = open_bank("token-account");
+        let token = client_token(&bank);
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_ne!(token, client_token(&bank));
+        assert!(pay_with(&bank, &token, "line-1", 100).is_ok());
+        assert_eq!(balance(&bank, CLIENT), eur(4900));
+        let journal = fs::read_to_string(path.with_extension("journal")).unwrap();
+        assert!(!journal.contains(&token));
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn account_names_are_not_tokens() {
+        let (bank, path) = open_bank("raw-account");
+        assert_eq!(
+            pay_with(&bank, CLIENT, "line-1", 100),
+            Err("Unknown payment token".to_string())
+        );
+        assert_eq!(balance(&bank, CLIENT), eur(5000));
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn expired_and_revoked_tokens_are_distinct_errors() {
+        let (bank, path) = open_bank("token-errors");
+        let expired = bank.borrow_mut().issue_token(CLIENT, 0).unwrap();
+        assert_eq!(
+            pay_with(&bank, &expired, "line-1", 100),
+            Err("Expired payment token".to_string())
+        );
+        let revoked = client_token(&bank);
+        assert!(bank.borrow_mut().revoke_token(&revoked).unwrap());
+        assert_eq!(
+            pay_with(&bank, &revoked, "line-1", 100),
+            Err("Revoked payment token".to_string())
+        );
+        assert!(!bank.borrow_mut().revoke_token("tok_missing").unwrap());
+        assert_eq!(balance(&bank, CLIENT), eur(5000));
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn tokens_of_closed_accounts_are_unknown_destinations() {
+        let mut bank = Bank::demo();
+        let from = bank.issue_token(CLIENT, TOKEN_TTL).unwrap();
+        let to = bank.issue_token("usd_client", TOKEN_TTL).unwrap();
+        assert!(bank.resolve(&from, &to).is_ok());
+        bank.accounts.remove("usd_client");
+        assert_eq!(
+            bank.resolve(&from, &to),
+            Err("Unknown destination account".to_string())
+        );
+    }
+
+    #[test]
+    fn tokens_survive_reopening_the_ledger() {
+        let (bank, path) = open_bank("token-reopen");
+        let token = client_token(&bank);
+        let revoked = client_token(&bank);
+        bank.borrow_mut().revoke_token(&revoked).unwrap();
+        drop(bank);
+        let bank = Rc::new(RefCell::new(Bank::open(&path).unwrap()));
+        assert!(pay_with(&bank, &token, "line-1", 100).is_ok());
+        assert_eq!(
+            pay_with(&bank, &revoked, "line-2", 100),
+            Err("Revoked payment token".to_string())
+        );
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn transfers_between_currencies_record_the_rate() {
+        let (bank, path) = open_bank("currencies");
+        let mut rates = RateTable::default();
+        rates.insert(Currency::EUR, Currency::GBP, "0.85".parse().unwrap());
+        bank.borrow_mut().set_rates(rates);
+        let token = bank
+            .borrow_mut()
+            .issue_token("gbp_client", TOKEN_TTL)
+            .unwrap();
+        assert!(pay_with(&bank, &token, "line-1", 100).is_ok());
+        assert_eq!(
+            balance(&bank, "gbp_client"),
+            Money::new(400_000 - 8_500, Currency::GBP)
+        );
+        assert_eq!(balance(&bank, AGENCY), eur(50100));
+        let debit = bank
+            .borrow()
+            .journal()
+            .iter()
+            .rev()
+            .nth(1)
+            .cloned()
+            .unwrap();
+        assert_eq!(debit.rate, Some("0.85".parse().unwrap()));
+        assert!(bank.borrow().verify().is_ok());
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn transfers_without_a_rate_fail() {
+        let (bank, path) = open_bank("no-rate");
+        let token = bank
+            .borrow_mut()
+            .issue_token("usd_client", TOKEN_TTL)
+            .unwrap();
+        assert_eq!(
+            pay_with(&bank, &token, "line-1", 100),
+            Err("No exchange rate from EUR to USD".to_string())
+        );
+        assert_eq!(
+            balance(&bank, "usd_client"),
+            Money::new(600_000, Currency::USD)
+        );
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn transaction_limit_rejects_with_reason() {
+        let (bank, path) = open_bank("transaction-limit");
+        let error = risk_error(check(&bank, "big-1", eur(5001)));
+        assert_eq!(
+            error.state.risk,
+            Some(RiskReason::TransactionLimit {
+                amount: eur(5001),
+                limit: eur(5000),
+            })
+        );
+        error.finish();
+        assert_eq!(balance(&bank, CLIENT), eur(5000));
+        assert!(bank.borrow().settled("big-1").is_none());
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn limits_are_converted_to_the_account_currency() {
+        let (bank, path) = open_bank("risk-currency");
+        let mut rates = RateTable::default();
+        rates.insert(Currency::EUR, Currency::GBP, "0.85".parse().unwrap());
+        bank.borrow_mut().set_rates(rates);
+        let token = bank
+            .borrow_mut()
+            .issue_token("gbp_client", TOKEN_TTL)
+            .unwrap();
+        let transaction = Transaction::<AccountValidation>::start_transaction(
+            bank.clone(),
+            None,
+            &token,
+            AGENCY,
+            Money::from_major(4500, Currency::GBP).unwrap(),
+        );
+        let error = match transaction.validate_accounts() {
+            AccountValidationResult::RiskCheck(checked) => risk_error(checked.check_risk()),
+            _ => panic!("accounts should be valid"),
+        };
+        assert_eq!(
+            error.state.risk,
+            Some(RiskReason::TransactionLimit {
+                amount: Money::from_major(4500, Currency::GBP).unwrap(),
+                limit: Money::from_major(4250, Currency::GBP).unwrap(),
+            })
+        );
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn daily_limit_counts_payments_and_holds() {
+        let (bank, path) = open_bank("daily-limit");
+        bank.borrow_mut()
+            .accounts
+            .insert(CLIENT.to_string(), eur(20000));
+        for key in &["day-1", "day-2"] {
+            assert!(pay(&bank, key, 4000).is_ok());
+        }
+        match check(&bank, "day-3", eur(1000)) {
+            RiskResult::Valid(valid) => match valid.authorize() {
+                AuthorizationResult::Authorized(authorized) => {
+                    let error = risk_error(check(&bank, "day-4", eur(1500)));
+                    assert_eq!(
+                        error.state.risk,
+                        Some(RiskReason::DailyLimit {
+                            spent: eur(9000),
+                            amount: eur(1500),
+                            limit: eur(10000),
+                        })
+                    );
+                    authorized.void();
+                }
+                AuthorizationResult::Challenge(_) => {
+                    panic!("the client already passed a challenge")
+                }
+                AuthorizationResult::Error(error) => panic!("{}", error.state.message),
+            },
+            _ => panic!("day-3 should pass the risk checks"),
+        }
+        // the voided hold no longer counts
+        assert!(matches!(
+            check(&bank, "day-4", eur(1500)),
+            RiskResult::Valid(_)
+        ));
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn velocity_rejects_bursts_of_transactions() {
+        let (bank, path) = open_bank("velocity");
+        bank.borrow_mut().risk.velocity_count = 3;
+        for key in &["burst-1", "burst-2", "burst-3"] {
+            assert!(pay(&bank, key, 10).is_ok());
+        }
+        let error = risk_error(check(&bank, "burst-4", eur(10)));
+        assert_eq!(
+            error.state.risk,
+            Some(RiskReason::Velocity {
+                count: 3,
+                window: 60,
+            })
+        );
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn large_amounts_wait_for_review() {
+        let (bank, path) = open_bank("review");
+        let review = match check(&bank, "large-1", eur(3000)) {
+            RiskResult::Review(review) => review,
+            _ => panic!("large-1 should be sent to review"),
+        };
+        assert_eq!(
+            review.state.reason,
+            RiskReason::LargeAmount {
+                amount: eur(3000),
+                threshold: eur(2000),
+            }
+        );
+        let error = review.reject();
+        assert!(error.state.message.starts_with("Rejected after review"));
+        assert_eq!(balance(&bank, CLIENT), eur(5000));
+
+        match check(&bank, "large-2", eur(3000)) {
+            RiskResult::Review(review) => match review.approve().perform_transaction() {
+                TransactionResult::Finish(finish) => finish.finish(),
+                TransactionResult::Error(error) => panic!("{}", error.state.message),
+            },
+            _ => panic!("large-2 should be sent to review"),
+        }
+        assert_eq!(balance(&bank, CLIENT), eur(2000));
+        assert!(bank.borrow().verify().is_ok());
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn trusted_accounts_skip_the_risk_checks() {
+        let (bank, path) = open_bank("trusted");
+        let token = bank.borrow_mut().issue_token(AGENCY, TOKEN_TTL).unwrap();
+        let transaction = Transaction::<AccountValidation>::start_transaction(
+            bank.clone(),
+            None,
+            &token,
+            CLIENT,
+            eur(8000),
+        );
+        match transaction.validate_accounts() {
+            AccountValidationResult::RiskCheck(checked) => {
+                assert!(matches!(checked.check_risk(), RiskResult::Valid(_)))
+            }
+            _ => panic!("accounts should be valid"),
+        }
+        remove_bank(&path);
+    }
+
+    /// Authorize `amount` for the client, expecting the bank to challenge it.
+    fn challenged(bank: &Rc<RefCell<Bank>>, key: &str, amount: Money) -> Transaction<Challenge> {
+        match check(bank, key, amount) {
+            RiskResult::Valid(valid) => match valid.authorize() {
+                AuthorizationResult::Challenge(challenge) => challenge,
+                AuthorizationResult::Authorized(_) => panic!("{} was not challenged", key),
+                AuthorizationResult::Error(error) => panic!("{}", error.state.message),
+            },
+            _ => panic!("{} should pass the risk checks", key),
+        }
+    }
+
+    #[test]
+    fn large_holds_are_challenged() {
+        let (bank, path) = open_bank("challenge");
+        let challenge = challenged(&bank, "hold-1", eur(1500));
+        assert_eq!(challenge.state.code.len(), 6);
+        assert!(bank.borrow().holds.is_empty());
+
+        let code = challenge.state.code.clone();
+        let challenge = match challenge.confirm("not-the-code") {
+            ChallengeResult::Challenge(challenge) => challenge,
+            _ => panic!("a wrong code should be asked again"),
+        };
+        assert_eq!(challenge.state.attempts, CHALLENGE_ATTEMPTS - 1);
+        match challenge.confirm(&code) {
+            ChallengeResult::Authorized(authorized) => {
+                assert_eq!(authorized.state.held, eur(1500));
+                authorized.void();
+            }
+            _ => panic!("the right code should authorize the payment"),
+        }
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn challenges_count_what_is_already_held() {
+        let (bank, path) = open_bank("challenge-held");
+        let first = match check(&bank, "line-1", eur(600)) {
+            RiskResult::Valid(valid) => match valid.authorize() {
+                AuthorizationResult::Authorized(authorized) => authorized,
+                _ => panic!("line-1 should be authorized"),
+            },
+            _ => panic!("line-1 should pass the risk checks"),
+        };
+        // together with the first line the purchase goes over the threshold
+        let challenge = challenged(&bank, "line-2", eur(600));
+        let code = challenge.state.code.clone();
+        let second = match challenge.confirm(&code) {
+            ChallengeResult::Authorized(authorized) => authorized,
+            _ => panic!("the right code should authorize the payment"),
+        };
+        // a passed challenge covers the next lines
+        match check(&bank, "line-3", eur(600)) {
+            RiskResult::Valid(valid) => match valid.authorize() {
+                AuthorizationResult::Authorized(authorized) => authorized.void(),
+                _ => panic!("line-3 should be authorized without a challenge"),
+            },
+            _ => panic!("line-3 should pass the risk checks"),
+        }
+        first.void();
+        second.void();
+        remove_bank(&path);
+    }
+
+    #[test]
+    fn wrong_codes_fail_the_transaction() {
+        let (bank, path) = open_bank("challenge-failed");
+        let mut challenge = challenged(&bank, "wrong-1", eur(1500));
+        for _ in 1..CHALLENGE_ATTEMPTS {
+            challenge = match challenge.confirm("000000x") {
+                ChallengeResult::Challenge(challenge) => challenge,
+                _ => panic!("attempts should be left"),
+            };
+        }
+        match challenge.confirm("000000x") {
+            ChallengeResult::Error(error) => {
+                assert_eq!(error.state.message, "Too many wrong confirmation codes")
+            }
+            _ => panic!("the last wrong code should fail the transaction"),
+        }
+        assert!(bank.borrow().holds.is_empty());
+        assert!(bank.borrow().settled("wrong-1").is_none());
+        remove_bank(&path);
+    }
+}
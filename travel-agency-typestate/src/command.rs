@@ -0,0 +1,632 @@
+use std::{fmt, str::FromStr};
+use travel_agency_common::{
+    itinerary::RouteOrder,
+    money::Money,
+    query::SearchQuery,
+    shell::{self, SplitError},
+};
+
+const LOGIN: &str = "login";
+const REGISTER: &str = "register";
+const ADMIN: &str = "admin";
+const SEARCH: &str = "search";
+const ROUTE: &str = "route";
+const SELECT: &str = "select";
+const REMOVE: &str = "remove";
+const CART: &str = "cart";
+const QUOTE: &str = "quote";
+const BUY: &str = "buy";
+const CONFIRM: &str = "confirm";
+const CANCEL: &str = "cancel";
+const APPROVE: &str = "approve";
+const REJECT: &str = "reject";
+const CHECK: &str = "check";
+const RETRY: &str = "retry";
+const CONTINUE: &str = "continue";
+const LOGOUT: &str = "logout";
+/// `logout save` keeps the cart for the next login.
+const SAVE: &str = "save";
+/// Save the session and exit, it is resumed on the next start.
+const SUSPEND: &str = "suspend";
+const CLOSE: &str = "close";
+const TRIPS: &str = "trips";
+const ADD: &str = "add";
+const EDIT: &str = "edit";
+const REPRICE: &str = "reprice";
+const RETIRE: &str = "retire";
+const USERS: &str = "users";
+const BALANCES: &str = "balances";
+const REVIEWS: &str = "reviews";
+const HELP: &str = "help";
+
+// `TRANSITIONS`, the transitions of each state of the session automaton,
+// generated by the build script from `src/agency.rs`
+include!(concat!(env!("OUT_DIR"), "/transitions.rs"));
+
+/// Every command with its usage and the transition of the session automaton it takes.
+///
+/// Commands without a transition belong to the REPL itself and are available in every state.
+const COMMANDS: &[(&str, &str, Option<&str>)] = &[
+    (LOGIN, "login <username> <password>", Some("login")),
+    (
+        REGISTER,
+        "register <username> <password> <email>",
+        Some("register"),
+    ),
+    (ADMIN, "admin <username> <password>", Some("admin_login")),
+    (
+        SEARCH,
+        "search [city] [from:<city>] [to:<city>] [max:<price>] [sort:price|from|to]",
+        Some("search_trip"),
+    ),
+    (
+        ROUTE,
+        "route <from> <to> [cheapest|legs]",
+        Some("search_route"),
+    ),
+    (SELECT, "select <idx>", Some("add_trip")),
+    (REMOVE, "remove <idx>", Some("remove_trip")),
+    (CART, "cart", Some("cart")),
+    (QUOTE, "quote", Some("quote")),
+    (BUY, "buy <token>", Some("buy")),
+    (CONFIRM, "confirm <code>", Some("confirm")),
+    (CHECK, "check", Some("check")),
+    (CANCEL, "cancel", Some("cancel")),
+    (RETRY, "retry", Some("retry")),
+    (CONTINUE, "continue", Some("remove_sold_out")),
+    (TRIPS, "trips", Some("trips")),
+    (
+        ADD,
+        "add <from> <to> <capacity> <price> [currency]",
+        Some("create_trip"),
+    ),
+    (EDIT, "edit <id> <from> <to> <capacity>", Some("edit_trip")),
+    (
+        REPRICE,
+        "reprice <id> <price> [currency]",
+        Some("reprice_trip"),
+    ),
+    (RETIRE, "retire <id>", Some("retire_trip")),
+    (USERS, "users", Some("users")),
+    (BALANCES, "balances", Some("balances")),
+    (REVIEWS, "reviews", Some("reviews")),
+    (APPROVE, "approve <id>", Some("approve")),
+    (REJECT, "reject <id>", Some("reject")),
+    (LOGOUT, "logout [save]", Some("logout")),
+    (CLOSE, "close", Some("close")),
+    (SUSPEND, "suspend", None),
+    (HELP, "help", None),
+];
+
+/// Usages that differ from `COMMANDS` in a state, by state and command.
+///
+/// Expired sessions log back in with the password alone and always keep the cart on logout,
+/// sessions without a cart, or whose cart waits for review, have nothing to save.
+const STATE_USAGES: &[(&str, &str, &str)] = &[
+    ("Empty", LOGOUT, "logout"),
+    ("Admin", LOGOUT, "logout"),
+    ("AwaitingReview", LOGOUT, "logout"),
+    ("Expired", LOGIN, "login <password>"),
+    ("Expired", LOGOUT, "logout"),
+];
+
+/// Commands whose arguments include a password, they are never kept in the line history.
+const WITH_PASSWORD: &[&str] = &[LOGIN, REGISTER, ADMIN];
+
+/// A line typed in the REPL, checked against the arguments its command takes.
+///
+/// Whether the command is available depends on the state of the session.
+#[derive(Clone, Debug)]
+pub enum Command {
+    /// Expired sessions log back in with the password alone.
+    Login {
+        username: Option<String>,
+        password: String,
+    },
+    Register {
+        username: String,
+        password: String,
+        email: String,
+    },
+    Admin {
+        username: String,
+        password: String,
+    },
+    Search(SearchQuery),
+    Route {
+        from: String,
+        to: String,
+        order: RouteOrder,
+    },
+    Select(usize),
+    Remove(usize),
+    Cart,
+    Quote,
+    Buy(String),
+    Confirm(String),
+    /// Carry on with a purchase once its payment was reviewed.
+    Check,
+    Cancel,
+    Retry,
+    Continue,
+    Logout {
+        save_cart: bool,
+    },
+    Suspend,
+    Close,
+    Trips,
+    AddTrip {
+        from: String,
+        to: String,
+        capacity: usize,
+        price: Money,
+    },
+    EditTrip {
+        id: usize,
+        from: String,
+        to: String,
+        capacity: usize,
+    },
+    Reprice {
+        id: usize,
+        price: Money,
+    },
+    Retire(usize),
+    Users,
+    Balances,
+    Reviews,
+    /// Approve the payment under review with this id.
+    Approve(usize),
+    /// Reject the payment under review with this id.
+    Reject(usize),
+    Help,
+}
+
+impl Command {
+    /// The word the command is typed with.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Login { .. } => LOGIN,
+            Command::Register { .. } => REGISTER,
+            Command::Admin { .. } => ADMIN,
+            Command::Search(_) => SEARCH,
+            Command::Route { .. } => ROUTE,
+            Command::Select(_) => SELECT,
+            Command::Remove(_) => REMOVE,
+            Command::Cart => CART,
+            Command::Quote => QUOTE,
+            Command::Buy(_) => BUY,
+            Command::Confirm(_) => CONFIRM,
+            Command::Check => CHECK,
+            Command::Cancel => CANCEL,
+            Command::Retry => RETRY,
+            Command::Continue => CONTINUE,
+            Command::Logout { .. } => LOGOUT,
+            Command::Suspend => SUSPEND,
+            Command::Close => CLOSE,
+            Command::Trips => TRIPS,
+            Command::AddTrip { .. } => ADD,
+            Command::EditTrip { .. } => EDIT,
+            Command::Reprice { .. } => REPRICE,
+            Command::Retire(_) => RETIRE,
+            Command::Users => USERS,
+            Command::Balances => BALANCES,
+            Command::Reviews => REVIEWS,
+            Command::Approve(_) => APPROVE,
+            Command::Reject(_) => REJECT,
+            Command::Help => HELP,
+        }
+    }
+}
+
+/// How the command `name` is used, `None` if there is no such command.
+pub fn usage(name: &str) -> Option<&'static str> {
+    COMMANDS
+        .iter()
+        .find(|(command, _, _)| *command == name)
+        .map(|(_, usage, _)| *usage)
+}
+
+/// How the command `name` is used in `state`.
+pub fn usage_in(state: &str, name: &str) -> Option<&'static str> {
+    STATE_USAGES
+        .iter()
+        .find(|(s, command, _)| *s == state && *command == name)
+        .map(|(_, _, usage)| *usage)
+        .or_else(|| usage(name))
+}
+
+/// Whether `line` gives a password, e.g. `login`, and must be kept out of the history.
+pub fn has_password(line: &str) -> bool {
+    line.split_whitespace()
+        .next()
+        .is_some_and(|name| WITH_PASSWORD.contains(&name))
+}
+
+/// The commands that can be used in `state`, with their usage.
+///
+/// A command is available when the state has the transition it takes.
+pub fn available(state: &str) -> Vec<(&'static str, &'static str)> {
+    let transitions = TRANSITIONS
+        .iter()
+        .find(|(name, _)| *name == state)
+        .map_or(&[][..], |(_, transitions)| transitions);
+    COMMANDS
+        .iter()
+        .filter(|(_, _, transition)| transition.is_none_or(|t| transitions.contains(&t)))
+        .map(|(command, _, _)| (*command, usage_in(state, command).unwrap_or(command)))
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommandError {
+    /// Nothing but whitespace was typed.
+    Empty,
+    Syntax(SplitError),
+    Unknown(String),
+    /// The arguments do not fit the command, `reason` says why when it is not only their number.
+    Usage {
+        command: &'static str,
+        reason: Option<String>,
+    },
+}
+
+impl CommandError {
+    fn usage(command: &'static str) -> Self {
+        CommandError::Usage {
+            command,
+            reason: None,
+        }
+    }
+
+    fn invalid<E: fmt::Display>(command: &'static str, reason: E) -> Self {
+        CommandError::Usage {
+            command,
+            reason: Some(reason.to_string()),
+        }
+    }
+
+    /// The error message, with the usage of the command in `state`.
+    pub fn message(&self, state: &str) -> String {
+        match self {
+            CommandError::Usage { command, reason } => {
+                usage_error(command, reason.as_deref(), usage_in(state, command))
+            }
+            err => err.to_string(),
+        }
+    }
+}
+
+fn usage_error(command: &str, reason: Option<&str>, usage: Option<&str>) -> String {
+    let mut message = format!("invalid {} command", command);
+    if let Some(reason) = reason {
+        message.push_str(&format!(": {}", reason));
+    }
+    message.push_str(&format!(". usage: {}", usage.unwrap_or(command)));
+    message
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Empty => write!(f, "empty command"),
+            CommandError::Syntax(err) => write!(f, "invalid command: {}", err),
+            CommandError::Unknown(name) => write!(f, "invalid command: {}", name),
+            CommandError::Usage { command, reason } => {
+                write!(
+                    f,
+                    "{}",
+                    usage_error(command, reason.as_deref(), usage(command))
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl FromStr for Command {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words = shell::split(s).map_err(CommandError::Syntax)?;
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (name.as_str(), args),
+            None => return Err(CommandError::Empty),
+        };
+        let command = match name {
+            LOGIN => match args {
+                [password] => Command::Login {
+                    username: None,
+                    password: password.clone(),
+                },
+                [username, password] => Command::Login {
+                    username: Some(username.clone()),
+                    password: password.clone(),
+                },
+                _ => return Err(CommandError::usage(LOGIN)),
+            },
+            REGISTER => match args {
+                [username, password, email] => Command::Register {
+                    username: username.clone(),
+                    password: password.clone(),
+                    email: email.clone(),
+                },
+                _ => return Err(CommandError::usage(REGISTER)),
+            },
+            ADMIN => match args {
+                [username, password] => Command::Admin {
+                    username: username.clone(),
+                    password: password.clone(),
+                },
+                _ => return Err(CommandError::usage(ADMIN)),
+            },
+            SEARCH => Command::Search(
+                SearchQuery::from_words(args).map_err(|err| CommandError::invalid(SEARCH, err))?,
+            ),
+            ROUTE => match args {
+                [from, to] | [from, to, _] => Command::Route {
+                    from: from.clone(),
+                    to: to.clone(),
+                    order: match args.get(2) {
+                        Some(order) => order
+                            .parse()
+                            .map_err(|err| CommandError::invalid(ROUTE, err))?,
+                        None => RouteOrder::default(),
+                    },
+                },
+                _ => return Err(CommandError::usage(ROUTE)),
+            },
+            SELECT => Command::Select(index(SELECT, args)?),
+            REMOVE => Command::Remove(index(REMOVE, args)?),
+            BUY => Command::Buy(single(BUY, args)?),
+            CONFIRM => Command::Confirm(single(CONFIRM, args)?),
+            LOGOUT => match args {
+                [] => Command::Logout { save_cart: false },
+                [save] if save == SAVE => Command::Logout { save_cart: true },
+                _ => return Err(CommandError::usage(LOGOUT)),
+            },
+            ADD => match args {
+                [from, to, capacity, price @ ..] if (1..=2).contains(&price.len()) => {
+                    Command::AddTrip {
+                        from: from.clone(),
+                        to: to.clone(),
+                        capacity: number(ADD, "capacity", capacity)?,
+                        price: money(ADD, price)?,
+                    }
+                }
+                _ => return Err(CommandError::usage(ADD)),
+            },
+            EDIT => match args {
+                [id, from, to, capacity] => Command::EditTrip {
+                    id: number(EDIT, "id", id)?,
+                    from: from.clone(),
+                    to: to.clone(),
+                    capacity: number(EDIT, "capacity", capacity)?,
+                },
+                _ => return Err(CommandError::usage(EDIT)),
+            },
+            REPRICE => match args {
+                [id, price @ ..] if (1..=2).contains(&price.len()) => Command::Reprice {
+                    id: number(REPRICE, "id", id)?,
+                    price: money(REPRICE, price)?,
+                },
+                _ => return Err(CommandError::usage(REPRICE)),
+            },
+            RETIRE => match args {
+                [id] => Command::Retire(number(RETIRE, "id", id)?),
+                _ => return Err(CommandError::usage(RETIRE)),
+            },
+            APPROVE => match args {
+                [id] => Command::Approve(number(APPROVE, "id", id)?),
+                _ => return Err(CommandError::usage(APPROVE)),
+            },
+            REJECT => match args {
+                [id] => Command::Reject(number(REJECT, "id", id)?),
+                _ => return Err(CommandError::usage(REJECT)),
+            },
+            _ => {
+                let command = match name {
+                    CART => Command::Cart,
+                    QUOTE => Command::Quote,
+                    CHECK => Command::Check,
+                    CANCEL => Command::Cancel,
+                    RETRY => Command::Retry,
+                    CONTINUE => Command::Continue,
+                    SUSPEND => Command::Suspend,
+                    CLOSE => Command::Close,
+                    TRIPS => Command::Trips,
+                    USERS => Command::Users,
+                    BALANCES => Command::Balances,
+                    REVIEWS => Command::Reviews,
+                    HELP => Command::Help,
+                    _ => return Err(CommandError::Unknown(name.to_string())),
+                };
+                if !args.is_empty() {
+                    return Err(CommandError::usage(command.name()));
+                }
+                command
+            }
+        };
+        Ok(command)
+    }
+}
+
+fn single(command: &'static str, args: &[String]) -> Result<String, CommandError> {
+    match args {
+        [arg] => Ok(arg.clone()),
+        _ => Err(CommandError::usage(command)),
+    }
+}
+
+fn index(command: &'static str, args: &[String]) -> Result<usize, CommandError> {
+    match args {
+        [idx] => number(command, "index", idx),
+        _ => Err(CommandError::usage(command)),
+    }
+}
+
+fn number(command: &'static str, what: &str, arg: &str) -> Result<usize, CommandError> {
+    arg.parse().map_err(|_| {
+        CommandError::invalid(command, format!("{} must be a number, got {}", what, arg))
+    })
+}
+
+/// A price typed as one word, `450.50`, or two, `450.50 USD`.
+fn money(command: &'static str, words: &[String]) -> Result<Money, CommandError> {
+    words
+        .join(" ")
+        .parse()
+        .map_err(|err| CommandError::invalid(command, format!("invalid price: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Command {
+        line.parse().unwrap()
+    }
+
+    fn error(line: &str) -> CommandError {
+        line.parse::<Command>().unwrap_err()
+    }
+
+    #[test]
+    fn parses_quoted_arguments() {
+        let query = match parse(r#"search "New York""#) {
+            Command::Search(query) => query,
+            command => panic!("parsed {:?}", command),
+        };
+        assert_eq!(query.city.as_deref(), Some("New York"));
+        let query = match parse(r#"search from:Lisbon to:"New York" max:500"#) {
+            Command::Search(query) => query,
+            command => panic!("parsed {:?}", command),
+        };
+        assert_eq!(query.from.as_deref(), Some("Lisbon"));
+        assert_eq!(query.to.as_deref(), Some("New York"));
+        assert!(matches!(
+            parse(r#"route Lisbon "New York" legs"#),
+            Command::Route { from, to, order: RouteOrder::FewestLegs } if from == "Lisbon" && to == "New York"
+        ));
+    }
+
+    #[test]
+    fn parses_arguments() {
+        assert!(matches!(parse("select 2"), Command::Select(2)));
+        assert!(matches!(
+            parse("login client secret"),
+            Command::Login { username: Some(username), password } if username == "client" && password == "secret"
+        ));
+        assert!(matches!(
+            parse("login secret"),
+            Command::Login { username: None, .. }
+        ));
+        assert!(matches!(
+            parse("logout save"),
+            Command::Logout { save_cart: true }
+        ));
+        assert!(matches!(
+            parse("add Lisbon Porto 10 25.5 USD"),
+            Command::AddTrip { capacity: 10, price, .. } if price == "25.50 USD".parse().unwrap()
+        ));
+    }
+
+    #[test]
+    fn reports_usage_errors() {
+        assert_eq!(
+            error("select abc"),
+            CommandError::Usage {
+                command: SELECT,
+                reason: Some("index must be a number, got abc".to_string()),
+            }
+        );
+        assert_eq!(
+            error("select abc").to_string(),
+            "invalid select command: index must be a number, got abc. usage: select <idx>"
+        );
+        assert_eq!(error("select"), CommandError::usage(SELECT));
+        assert_eq!(error("select 1 2"), CommandError::usage(SELECT));
+        assert_eq!(error("cart extra"), CommandError::usage(CART));
+        assert_eq!(error("logout maybe"), CommandError::usage(LOGOUT));
+        assert!(matches!(
+            error("add a b x 5"),
+            CommandError::Usage {
+                command: ADD,
+                reason: Some(_)
+            }
+        ));
+        assert!(matches!(
+            error("search"),
+            CommandError::Usage {
+                command: SEARCH,
+                reason: Some(_)
+            }
+        ));
+    }
+
+    #[test]
+    fn reports_syntax_and_unknown_commands() {
+        assert_eq!(error("   "), CommandError::Empty);
+        assert_eq!(
+            error(r#"search "New York"#),
+            CommandError::Syntax(SplitError::UnterminatedQuote('"'))
+        );
+        assert_eq!(error("bogus"), CommandError::Unknown("bogus".to_string()));
+    }
+
+    #[test]
+    fn every_command_has_a_transition_or_none() {
+        for (name, usage, transition) in COMMANDS {
+            assert!(usage.starts_with(name), "{}", usage);
+            if let Some(transition) = transition {
+                assert!(
+                    TRANSITIONS
+                        .iter()
+                        .any(|(_, transitions)| transitions.contains(transition)),
+                    "no state has the transition {}",
+                    transition
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lists_the_commands_of_a_state() {
+        let names =
+            |state| -> Vec<_> { available(state).into_iter().map(|(name, _)| name).collect() };
+        assert_eq!(
+            names("Guest"),
+            [LOGIN, REGISTER, ADMIN, CLOSE, SUSPEND, HELP]
+        );
+        assert!(names("NonEmpty").contains(&BUY));
+        assert!(!names("Empty").contains(&BUY));
+        assert_eq!(names("Unknown"), [SUSPEND, HELP]);
+    }
+
+    #[test]
+    fn usage_depends_on_the_state() {
+        let usages = |state| -> Vec<_> { available(state).into_iter().map(|(_, u)| u).collect() };
+        assert!(usages("Expired").contains(&"login <password>"));
+        assert!(usages("Expired").contains(&"logout"));
+        assert!(usages("Guest").contains(&"login <username> <password>"));
+        assert!(usages("NonEmpty").contains(&"logout [save]"));
+        assert_eq!(
+            error("login").message("Expired"),
+            "invalid login command. usage: login <password>"
+        );
+        assert_eq!(
+            error("login").to_string(),
+            "invalid login command. usage: login <username> <password>"
+        );
+    }
+
+    #[test]
+    fn passwords_stay_out_of_the_history() {
+        assert!(has_password("login client secret"));
+        assert!(has_password("  admin boss secret"));
+        assert!(!has_password("approve 3"));
+        assert!(!has_password("search Lisbon"));
+        assert!(!has_password(""));
+    }
+}
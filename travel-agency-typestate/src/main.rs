@@ -1,197 +1,840 @@
 mod agency;
 mod bank;
+mod command;
+mod session;
 
-use agency::agency_api::*;
+use agency::{agency_api::*, Agency, PendingReview, PurchaseOutcome};
+use bank::{Bank, CHALLENGE_ATTEMPTS, TOKEN_TTL};
+use command::{Command, CommandError};
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Context, DefaultEditor, Editor, Helper,
+};
+use session::{CARTS_ENV, CARTS_FLAG, DEFAULT_CARTS, DEFAULT_SESSION, SESSION_ENV, SESSION_FLAG};
 use std::{
-    io::{stdin, stdout, Result, Write},
+    env, fs,
+    io::{self, Result},
+    process,
+    rc::Rc,
+};
+use travel_agency_common::{
+    catalog::{Catalog, Trip},
+    config,
+    itinerary::Itinerary,
+    users::UserStore,
 };
 
-const LOGIN: &'static str = "login";
-const SEARCH: &'static str = "search";
-const SELECT: &'static str = "select";
-const CLOSE: &'static str = "close";
-const BUY: &'static str = "buy";
-const RETRY: &'static str = "retry";
+const AUDIT_FLAG: &str = "--audit";
+const ISSUE_TOKEN_FLAG: &str = "--issue-token";
+const REVOKE_TOKEN_FLAG: &str = "--revoke-token";
+const IDLE_TIMEOUT_FLAG: &str = "--idle-timeout";
+const ADD_ADMIN_FLAG: &str = "--add-admin";
 
 fn main() -> Result<()> {
-    let mut input_buffer = String::new();
-    let mut session = TSession::new();
+    let catalog = match Catalog::from_env() {
+        Ok(catalog) => catalog,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    let mut users = match UserStore::from_env() {
+        Ok(users) => users,
+        Err(err) => {
+            eprintln!("could not open the user store: {}", err);
+            process::exit(1);
+        }
+    };
+    let mut bank = match Bank::from_env() {
+        Ok(bank) => bank,
+        Err(err) => {
+            eprintln!("could not open the bank ledger: {}", err);
+            process::exit(1);
+        }
+    };
+    if let Err(errors) = bank.verify() {
+        eprintln!("the bank ledger does not match its journal:");
+        for error in errors {
+            eprintln!("  {}", error);
+        }
+    }
+    if let Some(account) = flag(AUDIT_FLAG) {
+        audit(&bank, account.as_deref());
+        return Ok(());
+    }
+    if let Some(account) = flag(ISSUE_TOKEN_FLAG) {
+        let account = account.unwrap_or_else(|| {
+            eprintln!("usage: {} <account>", ISSUE_TOKEN_FLAG);
+            process::exit(1);
+        });
+        match bank.issue_token(&account, TOKEN_TTL) {
+            Ok(token) => println!("{}", token),
+            Err(err) => {
+                eprintln!("could not issue a token: {}", err);
+                process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    if let Some(token) = flag(REVOKE_TOKEN_FLAG) {
+        let token = token.unwrap_or_else(|| {
+            eprintln!("usage: {} <token>", REVOKE_TOKEN_FLAG);
+            process::exit(1);
+        });
+        match bank.revoke_token(&token) {
+            Ok(true) => println!("token revoked"),
+            Ok(false) => println!("unknown token"),
+            Err(err) => {
+                eprintln!("could not revoke the token: {}", err);
+                process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    if let Some(username) = flag(ADD_ADMIN_FLAG) {
+        let username = username.unwrap_or_else(|| {
+            eprintln!("usage: {} <username>", ADD_ADMIN_FLAG);
+            process::exit(1);
+        });
+        // asked for rather than passed, so that it shows in neither ps nor the shell history
+        let password = match new_password() {
+            Ok(Some(password)) => password,
+            Ok(None) => {
+                eprintln!("the passwords do not match");
+                process::exit(1);
+            }
+            Err(err) => {
+                eprintln!("could not read the password: {}", err);
+                process::exit(1);
+            }
+        };
+        match users.add_admin(&username, &password) {
+            Ok(user) => println!("{} is an administrator", user.username),
+            Err(err) => {
+                eprintln!("could not add the administrator: {}", err);
+                process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    let mut agency = Agency::new(catalog, bank, users);
+    if let Some(timeout) = flag(IDLE_TIMEOUT_FLAG) {
+        match timeout.and_then(|timeout| timeout.parse().ok()) {
+            Some(timeout) => agency.idle_timeout = timeout,
+            None => {
+                eprintln!("usage: {} <seconds>", IDLE_TIMEOUT_FLAG);
+                process::exit(1);
+            }
+        }
+    }
+    let carts_path = config::path_arg(CARTS_FLAG, CARTS_ENV, DEFAULT_CARTS);
+    match agency.open_carts(&carts_path) {
+        Ok(dropped) => {
+            for reason in dropped {
+                eprintln!("dropped {}", reason);
+            }
+        }
+        Err(err) => {
+            eprintln!(
+                "could not open the saved carts {}: {}",
+                carts_path.display(),
+                err
+            );
+            process::exit(1);
+        }
+    }
+    let agency = Rc::new(agency);
+    let mut editor = Editor::<Completion, DefaultHistory>::new().map_err(readline_error)?;
+    editor.set_helper(Some(Completion::default()));
+    let session_path = config::path_arg(SESSION_FLAG, SESSION_ENV, DEFAULT_SESSION);
+    let mut session = match TSession::resume(agency.clone(), &session_path) {
+        Ok(Some(session)) => {
+            println!("resumed the saved session");
+            fs::remove_file(&session_path)?;
+            session
+        }
+        Ok(None) => TSession::new(agency),
+        Err(err) => {
+            eprintln!("could not resume the saved session: {}", err);
+            TSession::new(agency)
+        }
+    };
     loop {
-        prompt(&mut input_buffer, &session)?;
-        let split_input: Vec<_> = input_buffer.trim().split(" ").collect();
-        println!("{:?}", split_input);
-        if let Some(&cmd) = split_input.first() {
-            session = match session {
-                TSession::Guest(s) => match cmd {
-                    LOGIN => {
-                        if split_input.len() != 3 {
-                            println!("invalid login command. usage: login <username> <password>");
-                            s.into()
-                        } else {
-                            match s.login(split_input.get(1).unwrap(), split_input.get(2).unwrap())
-                            {
-                                Login::Empty(empty) => {
-                                    println!("login successful");
-                                    empty.into()
-                                }
-                                Login::Error(error) => error.into(),
-                            }
-                        }
-                    }
-                    CLOSE => {
-                        println!("goodbye!");
-                        // NOTE breaks disable the "one-line handler" approach
-                        break;
+        let line = match prompt(&mut editor, &session) {
+            Ok(line) => line,
+            // ctrl-c drops the line being typed
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => {
+                // end of input, keep the session for the next start
+                println!();
+                session.save(&session_path)?;
+                break;
+            }
+            Err(err) => return Err(readline_error(err)),
+        };
+        let command = match line.parse::<Command>() {
+            Ok(command) => command,
+            Err(CommandError::Empty) => continue,
+            Err(err) => {
+                println!("{}", err.message(&session.to_string()));
+                continue;
+            }
+        };
+        if let Command::Suspend = command {
+            session.save(&session_path)?;
+            println!("session saved, it will be resumed on the next start");
+            break;
+        }
+        let (touched, expired) = touch(session);
+        session = touched;
+        if expired {
+            continue;
+        }
+        if let Command::Help = command {
+            println!("available commands:");
+            for (_, usage) in command::available(&session.to_string()) {
+                println!("  {}", usage);
+            }
+            continue;
+        }
+        // the history of a user is not left to the next one
+        let logout = matches!(command, Command::Logout { .. });
+        session = match session {
+            TSession::Guest(s) => match command {
+                Command::Login {
+                    username: Some(username),
+                    password,
+                } => login_result(s.login(&username, &password)),
+                command @ Command::Login { username: None, .. } => {
+                    let usage = CommandError::Usage {
+                        command: command.name(),
+                        reason: None,
+                    };
+                    println!("{}", usage);
+                    s.into()
+                }
+                Command::Register {
+                    username,
+                    password,
+                    email,
+                } => match s.register(&username, &password, &email) {
+                    Registration::Empty(empty) => {
+                        println!(
+                            "registration successful, welcome {}",
+                            empty.state.user.username
+                        );
+                        empty.into()
                     }
-                    _ => {
-                        println!("invalid command: {}", cmd);
-                        s.into()
+                    Registration::Error(error) => {
+                        println!(
+                            "registration failed: {}. use retry or close",
+                            error.state.message
+                        );
+                        error.into()
                     }
                 },
-                TSession::Empty(mut s) => match cmd {
-                    SEARCH => {
-                        if split_input.len() != 2 {
-                            println!("invalid search command. usage: search <keyword>")
-                        } else {
-                            let trips = s.search_trip(split_input[1]);
-                            for (i, trip) in trips.iter().enumerate() {
-                                println!("{}: {:?}", i, trip);
-                            }
+                Command::Admin { username, password } => {
+                    match s.admin_login(&username, &password) {
+                        AdminLogin::Admin(admin) => {
+                            println!(
+                                "admin login successful, welcome {}",
+                                admin.state.user.username
+                            );
+                            admin.into()
                         }
-                        s.into()
-                    }
-                    SELECT => {
-                        if split_input.len() != 2 {
-                            println!("invalid search command. usage: search <idx>");
-                            s.into()
-                        } else {
-                            let idx = split_input[1].parse::<usize>().unwrap();
-                            match s.add_trip(idx) {
-                                Selection::Empty(s) => {
-                                    println!("invalid index: {}", idx);
-                                    s.into()
-                                }
-                                Selection::NonEmpty(s) => s.into(),
-                            }
+                        AdminLogin::Error(error) => {
+                            println!(
+                                "admin login failed: {}. use retry or close",
+                                error.state.message
+                            );
+                            error.into()
                         }
                     }
-                    CLOSE => {
-                        s.close();
-                        println!("closing session!");
-                        break;
+                }
+                Command::Close => {
+                    s.close();
+                    println!("goodbye!");
+                    // NOTE breaks disable the "one-line handler" approach
+                    break;
+                }
+                command => {
+                    invalid(&command);
+                    s.into()
+                }
+            },
+            TSession::Empty(mut s) => match command {
+                Command::Cart => {
+                    if s.cart().is_empty() {
+                        println!("the cart is empty");
+                    }
+                    s.into()
+                }
+                Command::Search(query) => {
+                    let trips = s.search_trip(&query);
+                    for (i, trip) in trips.iter().enumerate() {
+                        println!("{}: {:?}", i, trip);
+                    }
+                    s.into()
+                }
+                Command::Route { from, to, order } => {
+                    let itineraries = s.search_route(&from, &to, order);
+                    for (i, itinerary) in itineraries.iter().enumerate() {
+                        println!("{}: {}", i, itinerary);
                     }
-                    _ => {
-                        println!("invalid command: {}", cmd);
+                    s.into()
+                }
+                Command::Select(idx) => match s.add_trip(idx) {
+                    Selection::Empty(s) => {
+                        println!("invalid index: {}", idx);
                         s.into()
                     }
+                    Selection::NonEmpty(s) => s.into(),
                 },
-                TSession::NonEmpty(mut s) => match cmd {
-                    SEARCH => {
-                        if split_input.len() != 2 {
-                            println!("invalid search command. usage: search <keyword>")
-                        } else {
-                            let trips = s.search_trip(split_input[1]);
-                            for (i, trip) in trips.iter().enumerate() {
-                                println!("{}: {:?}", i, trip);
-                            }
-                        }
-                        s.into()
+                Command::Logout { .. } => {
+                    println!("logged out");
+                    s.logout().into()
+                }
+                Command::Close => {
+                    s.close();
+                    println!("closing session!");
+                    break;
+                }
+                command => {
+                    invalid(&command);
+                    s.into()
+                }
+            },
+            TSession::NonEmpty(mut s) => match command {
+                Command::Search(query) => {
+                    let trips = s.search_trip(&query);
+                    for (i, trip) in trips.iter().enumerate() {
+                        println!("{}: {:?}", i, trip);
                     }
-                    SELECT => {
-                        if split_input.len() != 2 {
-                            println!("invalid search command. usage: search <idx>");
-                            s.into()
-                        } else {
-                            let idx = split_input[1].parse::<usize>().unwrap();
-                            match s.add_trip(idx) {
-                                Ok(()) => {}
-                                Err(s) => println!("{}", s),
-                            }
-                            s.into()
-                        }
+                    s.into()
+                }
+                Command::Route { from, to, order } => {
+                    let itineraries = s.search_route(&from, &to, order);
+                    for (i, itinerary) in itineraries.iter().enumerate() {
+                        println!("{}: {}", i, itinerary);
                     }
-                    BUY => {
-                        if split_input.len() != 2 {
-                            println!("invalid search command. usage: buy <token>");
-                            s.into()
-                        } else {
-                            match s.buy(split_input[1]) {
-                                Transaction::Empty(empty_sess) => empty_sess.into(),
-                                Transaction::RetryError(error) => error.into(),
-                            }
-                        }
+                    s.into()
+                }
+                Command::Select(idx) => {
+                    if let Err(err) = s.add_trip(idx) {
+                        println!("{}", err);
                     }
-                    CLOSE => {
-                        s.close();
-                        println!("closing session!");
-                        break;
+                    s.into()
+                }
+                Command::Cart => {
+                    for (i, itinerary) in s.cart().iter().enumerate() {
+                        println!("{}: {}", i, itinerary);
                     }
-                    _ => {
-                        println!("invalid command: {}", cmd);
-                        s.into()
+                    s.into()
+                }
+                Command::Quote => {
+                    match s.quote() {
+                        Ok(quote) => println!("{}", quote),
+                        Err(err) => println!("could not price the selection: {}", err),
                     }
-                },
-                TSession::RetryError(s) => match cmd {
-                    RETRY => s.retry().into(),
-                    CLOSE => {
-                        s.close();
-                        println!("closing session!");
-                        break;
-                    }
-                    _ => {
-                        println!("invalid command: {}", cmd);
+                    s.into()
+                }
+                Command::Remove(idx) => match s.cart_index(idx) {
+                    Ok(idx) => match s.remove_trip(idx) {
+                        Removal::NonEmpty(s) => s.into(),
+                        Removal::Empty(s) => {
+                            println!("selection is now empty");
+                            s.into()
+                        }
+                    },
+                    Err(err) => {
+                        println!("{}", err);
                         s.into()
                     }
                 },
-                TSession::Error(s) => match cmd {
-                    CLOSE => {
-                        s.close();
-                        println!("closing session!");
-                        break;
-                    }
-                    _ => {
-                        println!("invalid command: {}", cmd);
-                        s.into()
-                    }
+                Command::Buy(token) => {
+                    let selected = s.state.selected.clone();
+                    purchase_result(s.buy(&token), &selected)
+                }
+                Command::Logout { save_cart } => {
+                    logged_out(save_cart);
+                    s.logout(save_cart).into()
+                }
+                Command::Close => {
+                    s.close();
+                    println!("closing session!");
+                    break;
+                }
+                command => {
+                    invalid(&command);
+                    s.into()
+                }
+            },
+            TSession::AwaitingChallenge(s) => match command {
+                Command::Confirm(code) => {
+                    let selected = s.state.purchase.selected.clone();
+                    purchase_result(s.confirm(&code), &selected)
+                }
+                Command::Cancel => {
+                    println!("purchase cancelled");
+                    s.cancel().into()
+                }
+                Command::Close => {
+                    s.close();
+                    println!("closing session!");
+                    break;
+                }
+                command => {
+                    invalid(&command);
+                    s.into()
+                }
+            },
+            TSession::AwaitingReview(s) => match command {
+                Command::Check => {
+                    let selected = s.state.selected.clone();
+                    purchase_result(s.check(), &selected)
+                }
+                Command::Cancel => {
+                    println!("purchase cancelled");
+                    s.cancel().into()
+                }
+                Command::Logout { .. } => {
+                    println!("logged out, the payment stays under review until your next login");
+                    s.logout().into()
+                }
+                Command::Close => {
+                    s.close();
+                    println!("closing session!");
+                    break;
+                }
+                command => {
+                    invalid(&command);
+                    s.into()
+                }
+            },
+            TSession::RetryError(s) => match command {
+                Command::Retry => s.retry().into(),
+                Command::Logout { save_cart } => {
+                    logged_out(save_cart);
+                    s.logout(save_cart).into()
+                }
+                Command::Close => {
+                    s.close();
+                    println!("closing session!");
+                    break;
+                }
+                command => {
+                    invalid(&command);
+                    s.into()
+                }
+            },
+            TSession::SoldOut(s) => match command {
+                Command::Continue => match s.remove_sold_out() {
+                    Selection::Empty(s) => s.into(),
+                    Selection::NonEmpty(s) => s.into(),
                 },
-            };
+                Command::Close => {
+                    s.close();
+                    println!("closing session!");
+                    break;
+                }
+                command => {
+                    invalid(&command);
+                    s.into()
+                }
+            },
+            TSession::Admin(mut s) => match command {
+                Command::Trips => {
+                    for (trip, sold) in s.trips() {
+                        println!(
+                            "{}: {} -> {} ({}) {}/{} seats sold{}",
+                            trip.id,
+                            trip.from,
+                            trip.to,
+                            trip.price,
+                            sold,
+                            trip.capacity,
+                            if trip.retired { ", retired" } else { "" }
+                        );
+                    }
+                    s.into()
+                }
+                Command::AddTrip {
+                    from,
+                    to,
+                    capacity,
+                    price,
+                } => {
+                    trip_changed("added", s.create_trip(&from, &to, price, capacity));
+                    s.into()
+                }
+                Command::EditTrip {
+                    id,
+                    from,
+                    to,
+                    capacity,
+                } => {
+                    trip_changed("edited", s.edit_trip(id, &from, &to, capacity));
+                    s.into()
+                }
+                Command::Reprice { id, price } => {
+                    trip_changed("repriced", s.reprice_trip(id, price));
+                    s.into()
+                }
+                Command::Retire(id) => {
+                    trip_changed("retired", s.retire_trip(id));
+                    s.into()
+                }
+                Command::Users => {
+                    for user in s.users() {
+                        println!(
+                            "{} ({}) {}",
+                            user.username,
+                            user.role,
+                            user.email.as_deref().unwrap_or("")
+                        );
+                    }
+                    s.into()
+                }
+                Command::Balances => {
+                    for (account, balance, held) in s.balances() {
+                        match held {
+                            Some(held) => println!("{}: {}, {} held", account, balance, held),
+                            None => println!("{}: {}", account, balance),
+                        }
+                    }
+                    s.into()
+                }
+                Command::Reviews => {
+                    for review in s.reviews() {
+                        println!(
+                            "{}: {} pays {} for {}: {}",
+                            review.id,
+                            review.username,
+                            review.amount,
+                            review.itinerary,
+                            review.reason
+                        );
+                    }
+                    s.into()
+                }
+                Command::Approve(id) => {
+                    reviewed("approved", s.approve(id));
+                    s.into()
+                }
+                Command::Reject(id) => {
+                    reviewed("rejected", s.reject(id));
+                    s.into()
+                }
+                Command::Logout { .. } => {
+                    println!("logged out");
+                    s.logout().into()
+                }
+                Command::Close => {
+                    s.close();
+                    println!("closing session!");
+                    break;
+                }
+                command => {
+                    invalid(&command);
+                    s.into()
+                }
+            },
+            TSession::Expired(s) => match command {
+                Command::Login { username, password }
+                    if username
+                        .as_ref()
+                        .is_none_or(|name| *name == s.state.user.username) =>
+                {
+                    login_result(s.login(&password))
+                }
+                Command::Login { .. } => {
+                    println!(
+                        "the session of {} expired. usage: login <password>",
+                        s.state.user.username
+                    );
+                    s.into()
+                }
+                Command::Logout { .. } => {
+                    println!("logged out");
+                    s.logout().into()
+                }
+                Command::Close => {
+                    s.close();
+                    println!("closing session!");
+                    break;
+                }
+                command => {
+                    invalid(&command);
+                    s.into()
+                }
+            },
+            TSession::Error(s) => match command {
+                Command::Retry => s.retry().into(),
+                Command::Close => {
+                    s.close();
+                    println!("closing session!");
+                    break;
+                }
+                command => {
+                    invalid(&command);
+                    s.into()
+                }
+            },
+        };
+        if logout && matches!(session, TSession::Guest(_)) {
+            editor.clear_history().map_err(readline_error)?;
         }
     }
     Ok(())
 }
 
-fn prompt(input_buffer: &mut String, session: &TSession) -> Result<usize> {
-    input_buffer.clear();
-    let input = stdin();
-    let mut output = stdout();
-    output.write("(".as_bytes())?;
-    output.write(session.to_string().as_bytes())?;
-    output.write(")".as_bytes())?;
-    output.write("> ".as_bytes())?;
-    output.flush()?;
-    input.read_line(input_buffer)
+/// Read a line, completing the commands available in the state of `session`.
+fn prompt(
+    editor: &mut Editor<Completion, DefaultHistory>,
+    session: &TSession,
+) -> std::result::Result<String, ReadlineError> {
+    let state = session.to_string();
+    if let Some(completion) = editor.helper_mut() {
+        completion.commands = command::available(&state)
+            .into_iter()
+            .map(|(command, _)| command)
+            .collect();
+    }
+    let line = editor.readline(&format!("({})> ", state))?;
+    if !line.trim().is_empty() && !command::has_password(&line) {
+        editor.add_history_entry(line.as_str())?;
+    }
+    Ok(line)
+}
+
+fn readline_error(err: ReadlineError) -> io::Error {
+    match err {
+        ReadlineError::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+/// Tab completion of the command being typed.
+#[derive(Default)]
+struct Completion {
+    /// The commands available in the current state.
+    commands: Vec<&'static str>,
+}
+
+impl Completer for Completion {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let typed = &line[..pos];
+        let start = typed.len() - typed.trim_start().len();
+        let word = &typed[start..];
+        // only the command is completed, not its arguments
+        if word.contains(char::is_whitespace) {
+            return Ok((pos, vec![]));
+        }
+        let candidates = self
+            .commands
+            .iter()
+            .filter(|command| command.starts_with(word))
+            .map(|command| command.to_string())
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for Completion {
+    type Hint = String;
+}
+
+impl Highlighter for Completion {}
+
+impl Validator for Completion {}
+
+impl Helper for Completion {}
+
+/// Greet the user, showing the cart they saved if any, or report why the login failed.
+fn login_result(login: Login) -> TSession {
+    match login {
+        Login::Empty(empty) => {
+            println!("login successful, welcome {}", empty.state.user.username);
+            empty.into()
+        }
+        Login::NonEmpty(non_empty) => {
+            println!(
+                "login successful, welcome back {}. your saved cart:",
+                non_empty.state.user.username
+            );
+            for (i, itinerary) in non_empty.state.selected.iter().enumerate() {
+                println!("{}: {}", i, itinerary);
+            }
+            non_empty.into()
+        }
+        Login::AwaitingReview(review) => {
+            println!(
+                "login successful, welcome back {}",
+                review.state.user.username
+            );
+            println!(
+                "your payment of {} is under review {}. use check to carry on once it is reviewed, or cancel",
+                review.state.review.itinerary, review.state.review.id
+            );
+            review.into()
+        }
+        Login::Error(error) => {
+            println!("login failed: {}. use retry or close", error.state.message);
+            error.into()
+        }
+    }
+}
+
+/// Report the decision of an administrator on a payment under review.
+fn reviewed(decision: &str, result: std::result::Result<PendingReview, String>) {
+    match result {
+        Ok(review) => println!(
+            "{} payment {}: {} by {}",
+            decision, review.id, review.itinerary, review.username
+        ),
+        Err(err) => println!("could not review the payment: {}", err),
+    }
+}
+
+fn waiting_for_review(review: &PendingReview) {
+    println!(
+        "the payment of {} waits for review {}: {}. log out for an administrator to approve or reject it, then use check, or cancel",
+        review.itinerary, review.id, review.reason
+    );
+}
+
+/// Report the outcome of a change to the catalog.
+fn trip_changed(change: &str, result: std::result::Result<Trip, String>) {
+    match result {
+        Ok(trip) => println!(
+            "{} trip {}: {} -> {} ({}), {} seats",
+            change, trip.id, trip.from, trip.to, trip.price, trip.capacity
+        ),
+        Err(err) => println!("could not change the catalog: {}", err),
+    }
+}
+
+/// Record the activity of a logged in session, `true` if it just expired.
+fn touch(session: TSession) -> (TSession, bool) {
+    let activity = match session {
+        TSession::Empty(s) => s.touch(),
+        TSession::NonEmpty(s) => s.touch(),
+        session => return (session, false),
+    };
+    match activity {
+        Activity::Empty(s) => (s.into(), false),
+        Activity::NonEmpty(s) => (s.into(), false),
+        Activity::Expired(s) => {
+            session_expired();
+            (s.into(), true)
+        }
+    }
+}
+
+/// Report how a purchase of `selected` went and move on to the resulting state.
+fn purchase_result(transaction: Transaction, selected: &[Itinerary]) -> TSession {
+    match transaction {
+        Transaction::Empty(empty_sess) => {
+            println!("purchase successful");
+            for (i, itinerary) in selected.iter().enumerate() {
+                println!("{}: {} - {}", i, itinerary, PurchaseOutcome::Paid);
+            }
+            empty_sess.into()
+        }
+        Transaction::Expired(expired) => {
+            session_expired();
+            expired.into()
+        }
+        Transaction::AwaitingChallenge(challenge) => {
+            let challenge_state = &challenge.state.challenge.state;
+            if challenge_state.attempts < CHALLENGE_ATTEMPTS {
+                println!("wrong code, {} attempts left", challenge_state.attempts);
+            } else {
+                let itinerary = &selected[challenge.state.line];
+                println!(
+                    "the bank asks to confirm the payment of {}: code {} sent. use confirm <code> or cancel",
+                    itinerary, challenge_state.code
+                );
+            }
+            challenge.into()
+        }
+        Transaction::AwaitingReview(review) => {
+            waiting_for_review(&review.state.review);
+            review.into()
+        }
+        Transaction::RetryError(error) => {
+            println!("purchase failed: {}", error.state.message);
+            print_outcomes(&error.state.selected, &error.state.outcomes);
+            error.into()
+        }
+        Transaction::SoldOut(sold_out) => {
+            let trip = &sold_out.state.trip;
+            println!(
+                "no longer available: {} -> {}. use continue to remove it from the selection",
+                trip.from, trip.to
+            );
+            print_outcomes(&sold_out.state.selected, &sold_out.state.outcomes);
+            sold_out.into()
+        }
+    }
 }
 
-#[derive(Clone, Debug)]
-pub struct Trip {
-    from: String,
-    to: String,
-    price: usize,
+fn session_expired() {
+    println!(
+        "session expired after being idle, your cart is kept. use login <password>, logout or close"
+    );
 }
 
-impl Trip {
-    fn new(from: String, to: String, price: usize) -> Self {
-        Self { from, to, price }
+fn print_outcomes(selected: &[Itinerary], outcomes: &[PurchaseOutcome]) {
+    for (i, (itinerary, outcome)) in selected.iter().zip(outcomes).enumerate() {
+        println!("{}: {} - {}", i, itinerary, outcome);
     }
+}
 
-    fn matches(&self, city: &str) -> bool {
-        self.from == city || self.to == city
+fn logged_out(save_cart: bool) {
+    if save_cart {
+        println!("logged out, the cart is saved for your next login");
+    } else {
+        println!("logged out");
     }
+}
+
+/// Report a command that cannot be used in the current state.
+fn invalid(command: &Command) {
+    println!("invalid command: {}", command.name());
+}
+
+/// `None` when `name` was not given, otherwise the argument following it, if any.
+fn flag(name: &str) -> Option<Option<String>> {
+    let mut args = env::args().skip_while(|arg| arg != name);
+    args.next()?;
+    Some(args.next())
+}
+
+/// Read a new password twice from the terminal, `None` if the two differ.
+fn new_password() -> Result<Option<String>> {
+    let mut editor = DefaultEditor::new().map_err(readline_error)?;
+    let password = editor.readline("password: ").map_err(readline_error)?;
+    let repeated = editor
+        .readline("repeat the password: ")
+        .map_err(readline_error)?;
+    Ok((password == repeated).then_some(password))
+}
 
-    pub fn mocks() -> Vec<Self> {
-        let cities = vec!["Lisbon", "London", "Berlin", "Paris", "Amesterdam"];
-        (1..5)
-            .map(|i| Self::new(cities[i - 1].to_string(), cities[i].to_string(), i * 200))
-            .collect()
+/// Print the bank journal, optionally for a single account.
+fn audit(bank: &Bank, account: Option<&str>) {
+    let entries: Vec<_> = match account {
+        Some(account) => bank.account_journal(account).collect(),
+        None => bank.journal().iter().collect(),
+    };
+    for entry in entries {
+        println!("{}", entry);
     }
 }
@@ -0,0 +1,448 @@
+use crate::agency::{agency_api::*, Agency, PurchaseOutcome, SavedCart};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+    rc::Rc,
+};
+use travel_agency_common::{
+    catalog::{Catalog, Trip},
+    file,
+    itinerary::Itinerary,
+    users::User,
+};
+
+/// Command line flag used to pass the session path.
+pub const SESSION_FLAG: &str = "--session";
+/// Environment variable used when the flag is not present.
+pub const SESSION_ENV: &str = "TRAVEL_AGENCY_SESSION";
+/// Session file used when neither the flag nor the variable are set.
+pub const DEFAULT_SESSION: &str = "session.json";
+
+/// Command line flag used to pass the path of the carts saved on logout.
+pub const CARTS_FLAG: &str = "--carts";
+/// Environment variable used when the flag is not present.
+pub const CARTS_ENV: &str = "TRAVEL_AGENCY_CARTS";
+/// Carts file used when neither the flag nor the variable are set.
+pub const DEFAULT_CARTS: &str = "carts.json";
+
+/// Version written in every saved session.
+///
+/// Bump it when a state changes shape, sessions saved by a newer version are refused
+/// instead of being resumed into the wrong state.
+///
+/// Version 2 records when the user was last active, sessions saved before that expire
+/// on their first operation.
+pub const SESSION_VERSION: u32 = 2;
+
+/// A session saved to disk, `{"version":2,"state":"NonEmpty",...}`.
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedSession {
+    version: u32,
+    #[serde(flatten)]
+    state: SavedState,
+}
+
+/// The carts saved on logout, by username, `{"version":2,"carts":{"alice":{...}}}`.
+///
+/// They are written each time a cart is kept or restored, so that they outlive
+/// the process whether or not its session is saved.
+#[derive(Debug, Deserialize)]
+struct SavedCarts {
+    version: u32,
+    carts: HashMap<String, SavedCart>,
+}
+
+/// The fields of each state, without the agency every session shares.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "state")]
+enum SavedState {
+    Guest,
+    Error {
+        message: String,
+    },
+    Empty {
+        user: User,
+        last_search: Vec<Itinerary>,
+        #[serde(default)]
+        last_active: u64,
+    },
+    NonEmpty {
+        user: User,
+        last_search: Vec<Itinerary>,
+        selected: Vec<Itinerary>,
+        keys: Vec<String>,
+        #[serde(default)]
+        last_active: u64,
+    },
+    Expired {
+        user: User,
+        selected: Vec<Itinerary>,
+        keys: Vec<String>,
+    },
+    RetryError {
+        user: User,
+        message: String,
+        selected: Vec<Itinerary>,
+        keys: Vec<String>,
+        outcomes: Vec<PurchaseOutcome>,
+    },
+    SoldOut {
+        user: User,
+        trip: Trip,
+        selected: Vec<Itinerary>,
+        keys: Vec<String>,
+        outcomes: Vec<PurchaseOutcome>,
+    },
+}
+
+impl TSession {
+    /// Write the session to `path` so that [`TSession::resume`] can pick it up later.
+    ///
+    /// Bank holds only live as long as the process, a session waiting for a challenge
+    /// or a review cancels its purchase and is saved with the selection it was buying.
+    /// Admin sessions are not kept, the admin is logged out.
+    pub fn save<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        let state = match self {
+            TSession::Guest(_) => SavedState::Guest,
+            TSession::Admin(s) => {
+                s.logout();
+                SavedState::Guest
+            }
+            TSession::Error(s) => SavedState::Error {
+                message: s.state.message,
+            },
+            TSession::Empty(s) => SavedState::Empty {
+                user: s.state.user,
+                last_search: s.state.last_search,
+                last_active: s.state.last_active,
+            },
+            TSession::NonEmpty(s) => non_empty(s),
+            TSession::Expired(s) => SavedState::Expired {
+                user: s.state.user,
+                selected: s.state.selected,
+                keys: s.state.keys,
+            },
+            TSession::AwaitingChallenge(s) => non_empty(s.cancel()),
+            TSession::AwaitingReview(s) => non_empty(s.cancel()),
+            TSession::RetryError(s) => SavedState::RetryError {
+                user: s.state.user,
+                message: s.state.message,
+                selected: s.state.selected,
+                keys: s.state.keys,
+                outcomes: s.state.outcomes,
+            },
+            TSession::SoldOut(s) => SavedState::SoldOut {
+                user: s.state.user,
+                trip: s.state.trip,
+                selected: s.state.selected,
+                keys: s.state.keys,
+                outcomes: s.state.outcomes,
+            },
+        };
+        let saved = SavedSession {
+            version: SESSION_VERSION,
+            state,
+        };
+        let json = serde_json::to_string_pretty(&saved)?;
+        file::write_atomic(path, json)
+    }
+
+    /// Resume the session saved at `path`, `None` if there is none.
+    ///
+    /// The user must still exist and the saved trips must still be in the catalog,
+    /// their prices are the ones of the catalog now.
+    pub fn resume<P: AsRef<Path>>(agency: Rc<Agency>, path: P) -> io::Result<Option<TSession>> {
+        let json = match fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut saved: SavedSession = serde_json::from_str(&json)?;
+        if saved.version > SESSION_VERSION {
+            return Err(invalid(format!(
+                "session version {} is newer than the supported version {}",
+                saved.version, SESSION_VERSION
+            )));
+        }
+        restore(&agency, &mut saved.state)?;
+        let session = match saved.state {
+            SavedState::Guest => Session {
+                agency,
+                state: Guest,
+            }
+            .into(),
+            SavedState::Error { message } => Session {
+                agency,
+                state: Error { message },
+            }
+            .into(),
+            SavedState::Empty {
+                user,
+                last_search,
+                last_active,
+            } => Session {
+                agency,
+                state: Empty {
+                    user,
+                    last_search,
+                    last_active,
+                },
+            }
+            .into(),
+            SavedState::NonEmpty {
+                user,
+                last_search,
+                selected,
+                keys,
+                last_active,
+            } => Session {
+                agency,
+                state: NonEmpty {
+                    user,
+                    last_search,
+                    selected,
+                    keys,
+                    last_active,
+                },
+            }
+            .into(),
+            SavedState::Expired {
+                user,
+                selected,
+                keys,
+            } => Session {
+                agency,
+                state: Expired {
+                    user,
+                    selected,
+                    keys,
+                },
+            }
+            .into(),
+            SavedState::RetryError {
+                user,
+                message,
+                selected,
+                keys,
+                outcomes,
+            } => Session {
+                agency,
+                state: RetryError {
+                    user,
+                    message,
+                    selected,
+                    keys,
+                    outcomes,
+                },
+            }
+            .into(),
+            SavedState::SoldOut {
+                user,
+                trip,
+                selected,
+                keys,
+                outcomes,
+            } => Session {
+                agency,
+                state: SoldOut {
+                    user,
+                    trip,
+                    selected,
+                    keys,
+                    outcomes,
+                },
+            }
+            .into(),
+        };
+        Ok(Some(session))
+    }
+}
+
+impl Agency {
+    /// Keep the carts saved on logout in `path` from now on, restoring the ones saved there.
+    ///
+    /// Carts whose user is gone or whose trips are no longer in the catalog are dropped,
+    /// the reason of each is returned.
+    pub fn open_carts<P: AsRef<Path>>(&mut self, path: P) -> io::Result<Vec<String>> {
+        let path = path.as_ref();
+        let mut dropped = vec![];
+        match fs::read_to_string(path) {
+            Ok(json) => {
+                let saved: SavedCarts = serde_json::from_str(&json)?;
+                if saved.version > SESSION_VERSION {
+                    return Err(invalid(format!(
+                        "carts version {} is newer than the supported version {}",
+                        saved.version, SESSION_VERSION
+                    )));
+                }
+                let mut carts = self.carts.borrow_mut();
+                for (username, mut cart) in saved.carts {
+                    match restore_cart(self, &username, &mut cart) {
+                        Ok(()) => {
+                            carts.insert(username, cart);
+                        }
+                        Err(err) => dropped.push(format!("the cart of {}: {}", username, err)),
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        self.carts_path = Some(path.to_path_buf());
+        Ok(dropped)
+    }
+
+    /// Write the carts saved on logout to the file given to [`Agency::open_carts`], if any.
+    pub(crate) fn save_carts(&self) -> io::Result<()> {
+        let path = match &self.carts_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let carts = self.carts.borrow();
+        let json = serde_json::to_string_pretty(&serde_json::json!({
+            "version": SESSION_VERSION,
+            "carts": &*carts,
+        }))?;
+        file::write_atomic(path, json)
+    }
+}
+
+fn non_empty(s: Session<NonEmpty>) -> SavedState {
+    SavedState::NonEmpty {
+        user: s.state.user,
+        last_search: s.state.last_search,
+        selected: s.state.selected,
+        keys: s.state.keys,
+        last_active: s.state.last_active,
+    }
+}
+
+/// Refuse a saved state whose user is gone, whose trips no longer match the catalog
+/// or whose cart lines do not add up, and take every saved trip from the catalog.
+///
+/// Only the id and the cities of a saved trip are trusted, its price, capacity
+/// and whether it is retired come from the catalog.
+fn restore(agency: &Agency, state: &mut SavedState) -> io::Result<()> {
+    check_lines(state)?;
+    let (user, itineraries, trip): (_, Vec<&mut Itinerary>, _) = match state {
+        SavedState::Guest | SavedState::Error { .. } => return Ok(()),
+        SavedState::Empty {
+            user, last_search, ..
+        } => (user, last_search.iter_mut().collect(), None),
+        SavedState::NonEmpty {
+            user,
+            last_search,
+            selected,
+            ..
+        } => (user, last_search.iter_mut().chain(selected).collect(), None),
+        SavedState::Expired { user, selected, .. }
+        | SavedState::RetryError { user, selected, .. } => {
+            (user, selected.iter_mut().collect(), None)
+        }
+        SavedState::SoldOut {
+            user,
+            trip,
+            selected,
+            ..
+        } => (user, selected.iter_mut().collect(), Some(trip)),
+    };
+    if !agency.users.borrow().contains(&user.username) {
+        return Err(invalid(format!("unknown user {}", user.username)));
+    }
+    let catalog = agency.catalog.borrow();
+    for itinerary in itineraries {
+        if itinerary.legs.is_empty() {
+            return Err(invalid("an itinerary has no trips".to_string()));
+        }
+        for leg in &mut itinerary.legs {
+            *leg = from_catalog(&catalog, leg)?;
+        }
+    }
+    if let Some(trip) = trip {
+        *trip = from_catalog(&catalog, trip)?;
+    }
+    Ok(())
+}
+
+/// Refuse a selection without a payment key, or an outcome, for each of its lines.
+fn check_lines(state: &SavedState) -> io::Result<()> {
+    let (selected, keys, outcomes) = match state {
+        SavedState::NonEmpty { selected, keys, .. } => (selected, keys, None),
+        SavedState::Expired { selected, keys, .. } => (selected, keys, None),
+        SavedState::RetryError {
+            selected,
+            keys,
+            outcomes,
+            ..
+        }
+        | SavedState::SoldOut {
+            selected,
+            keys,
+            outcomes,
+            ..
+        } => (selected, keys, Some(outcomes)),
+        _ => return Ok(()),
+    };
+    // only an expired session may have had an empty cart
+    if selected.is_empty() && !matches!(state, SavedState::Expired { .. }) {
+        return Err(invalid("the selection is empty".to_string()));
+    }
+    if keys.len() != selected.len() {
+        return Err(invalid(format!(
+            "{} payment keys for {} selected itineraries",
+            keys.len(),
+            selected.len()
+        )));
+    }
+    match outcomes {
+        Some(outcomes) if outcomes.len() != selected.len() => Err(invalid(format!(
+            "{} outcomes for {} selected itineraries",
+            outcomes.len(),
+            selected.len()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Refuse a saved cart like the selection of a saved session, see [`restore`].
+fn restore_cart(agency: &Agency, username: &str, cart: &mut SavedCart) -> io::Result<()> {
+    if !agency.users.borrow().contains(username) {
+        return Err(invalid(format!("unknown user {}", username)));
+    }
+    if cart.selected.is_empty() {
+        return Err(invalid("the selection is empty".to_string()));
+    }
+    if cart.keys.len() != cart.selected.len() {
+        return Err(invalid(format!(
+            "{} payment keys for {} selected itineraries",
+            cart.keys.len(),
+            cart.selected.len()
+        )));
+    }
+    let catalog = agency.catalog.borrow();
+    for itinerary in &mut cart.selected {
+        if itinerary.legs.is_empty() {
+            return Err(invalid("an itinerary has no trips".to_string()));
+        }
+        for leg in &mut itinerary.legs {
+            *leg = from_catalog(&catalog, leg)?;
+        }
+    }
+    Ok(())
+}
+
+fn from_catalog(catalog: &Catalog, trip: &Trip) -> io::Result<Trip> {
+    catalog.current(trip).cloned().ok_or_else(|| {
+        invalid(format!(
+            "the trip {} -> {} is no longer in the catalog",
+            trip.from, trip.to
+        ))
+    })
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}